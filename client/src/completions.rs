@@ -0,0 +1,99 @@
+//! Shell completion scripts, printed by `--generate-completions`.
+
+use crate::flags::{Flag, FLAGS};
+pub use pasv_protocol::EASINGS;
+
+/// The shells there are scripts for.
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// The completion script for `shell`, or [`None`] if it isn't one of [`SHELLS`].
+pub fn script(shell: &str) -> Option<String> {
+    Some(match shell {
+        "bash" => bash(),
+        "zsh" => zsh(),
+        "fish" => fish(),
+        _ => return None,
+    })
+}
+
+fn bash() -> String {
+    let names = |with_value: bool| {
+        FLAGS
+            .iter()
+            .filter(|flag| flag.2 == with_value)
+            .flat_map(|Flag(long, short, _)| {
+                [
+                    Some(format!("--{long}")),
+                    short.map(|short| format!("-{short}")),
+                ]
+            })
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    let all = [names(false), names(true)].concat().join(" ");
+    let with_value = names(true).join("|");
+    format!(
+        r#"_pasv() {{
+    local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}
+    case $prev in
+        --easing|-e)
+            COMPREPLY=($(compgen -W "{easings}" -- "$cur"))
+            return
+            ;;
+        --generate-completions)
+            COMPREPLY=($(compgen -W "{shells}" -- "$cur"))
+            return
+            ;;
+        {with_value})
+            return
+            ;;
+    esac
+    if [[ $cur == -* ]]; then
+        COMPREPLY=($(compgen -W "{all}" -- "$cur"))
+    else
+        # the socket path
+        COMPREPLY=($(compgen -f -- "$cur"))
+    fi
+}}
+complete -F _pasv pasv
+"#,
+        easings = EASINGS.join(" "),
+        shells = SHELLS.join(" "),
+    )
+}
+
+fn zsh() -> String {
+    let mut script = "#compdef pasv\n\n_arguments \\\n".to_owned();
+    for Flag(long, short, with_value) in FLAGS {
+        let value = match *long {
+            "easing" => format!(":easing:({})", EASINGS.join(" ")),
+            "generate-completions" => format!(":shell:({})", SHELLS.join(" ")),
+            _ if *with_value => ":value:".to_owned(),
+            _ => String::new(),
+        };
+        script.push_str(&format!("    '--{long}{value}' \\\n"));
+        if let Some(short) = short {
+            script.push_str(&format!("    '-{short}{value}' \\\n"));
+        }
+    }
+    script.push_str("    '*:path:_files'\n");
+    script
+}
+
+fn fish() -> String {
+    let mut script = String::new();
+    for Flag(long, short, with_value) in FLAGS {
+        script.push_str(&format!("complete -c pasv -l {long}"));
+        if let Some(short) = short {
+            script.push_str(&format!(" -s {short}"));
+        }
+        match *long {
+            "easing" => script.push_str(&format!(" -x -a '{}'", EASINGS.join(" "))),
+            "generate-completions" => script.push_str(&format!(" -x -a '{}'", SHELLS.join(" "))),
+            _ if *with_value => script.push_str(" -x"),
+            _ => {}
+        }
+        script.push('\n');
+    }
+    script
+}
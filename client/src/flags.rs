@@ -0,0 +1,61 @@
+//! The flags of `pasv`, recognised by the parser in `main` and offered by the completion
+//! scripts.
+
+/// A flag: its long name, short name, and whether it takes a value.
+pub struct Flag(pub &'static str, pub Option<char>, pub bool);
+
+pub const FLAGS: &[Flag] = &[
+    Flag("get-volume", Some('g'), false),
+    Flag("status", Some('s'), false),
+    Flag("source", None, false),
+    Flag("json", None, false),
+    Flag("db", None, false),
+    Flag("raw", None, false),
+    Flag("channels", None, false),
+    Flag("precision", None, true),
+    Flag("live", None, false),
+    Flag("watch", None, false),
+    Flag("info", None, false),
+    Flag("metrics", None, false),
+    Flag("log", None, false),
+    Flag("list", Some('l'), false),
+    Flag("set-default", None, true),
+    Flag("crossfade", None, false),
+    Flag("balance", None, true),
+    Flag("toggle-preset", None, true),
+    Flag("mute", None, false),
+    Flag("unmute", None, false),
+    Flag("no-blip", None, false),
+    Flag("cancel", Some('c'), false),
+    Flag("envelope", None, true),
+    Flag("sleep", None, true),
+    Flag("cancel-sleep", None, false),
+    Flag("duration", Some('d'), true),
+    Flag("from", None, true),
+    Flag("easing", Some('e'), true),
+    Flag("gamma", None, true),
+    Flag("steps", None, true),
+    Flag("wait", Some('w'), false),
+    Flag("decimal-comma", None, false),
+    Flag("report-saturation", None, false),
+    Flag("timeout", None, true),
+    Flag("sink", None, true),
+    Flag("app", None, true),
+    Flag("dry-run", None, false),
+    Flag("no-wait", None, false),
+    Flag("generate-completions", None, true),
+    Flag("help", None, false),
+];
+
+/// Finds the flag `arg`, such as `--duration` or `-d`.
+pub fn find(arg: &str) -> Option<&'static Flag> {
+    if let Some(long) = arg.strip_prefix("--") {
+        return FLAGS.iter().find(|flag| flag.0 == long);
+    }
+    let mut shorts = arg.strip_prefix('-')?.chars();
+    let short = shorts.next()?;
+    if shorts.next().is_some() {
+        return None;
+    }
+    FLAGS.iter().find(|flag| flag.1 == Some(short))
+}
@@ -1,8 +1,15 @@
+mod completions;
+mod flags;
+
 use std::env;
-use std::io::{Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flags::Flag;
 
 fn print_help() -> ! {
     eprintln!(
@@ -12,78 +19,422 @@ Usage: pasv [OPTIONS] <VOLUME> [PATH]
 Volume:
     A decimal or percent value representing the volume.
     If prepended by a `+` or `-`, the volume change is relative.
+    A value ending in `dB` is in decibels, where `0dB` is 100%.
+    Relative decibel changes such as `+3dB` are relative to the current volume.
+    `-` reads the volume from a line of stdin. Whitespace in the volume, such as `50 %`, is
+    ignored.
 
 Path:
-    An optional path to the pasvd socket. Default to the user's run directory.\
+    An optional path to the pasvd socket. Default to the user's run directory.
+    A path starting with `@` is an abstract socket address.\
 \
 Options:
-    -g, --get-volume                Get the volume of the default sink. Returns the value in percents.
+    -g, --get-volume                Get the volume of the default sink. Returns the value in percents,
+                                    followed by `muted=<true|false>` and
+                                    `description=<DESCRIPTION>` on the next lines.
+    -s, --status                    Get whether a volume change is running. Returns `idle` or
+                                    `transitioning target=<VOLUME> current=<VOLUME>
+                                    <sink|source>=<NAME> description=<DESCRIPTION>`.
+        --source                    Act on the default source (microphone) instead of the sink.
+        --json                      With --get-volume, print a JSON object with the volume
+                                    (decimal), mute state, sink name, and description instead.
+        --db                        With --get-volume, print the volume in decibels instead,
+                                    such as `-6.0 dB`. Zero volume is `-inf dB`.
+        --raw                       With --get-volume, print the PulseAudio volume of the loudest
+                                    channel instead, where 65536 is 100%.
+        --channels                  With --get-volume, print the volume of each channel instead,
+                                    such as `L=45.00% R=43.00%`.
+        --precision [DIGITS]        With --get-volume, print the percents with this many decimals
+                                    instead of 2.
+        --live                      With --get-volume, print the volume reported by PulseAudio
+                                    during a volume change, instead of the one the change has
+                                    reached.
+        --watch                     Print the volume each time it changes, as `<VOLUME>%
+                                    <sink|source>=<NAME>`, until interrupted.
+        --info                      Print the version, protocol version, uptime in seconds, socket
+                                    path, default sink, whether volumes are clamped, and the
+                                    minimum and maximum volume of pasvd, as `key=value` lines.
+        --metrics                   Print the counters of pasvd since it started, as `key=value`
+                                    lines: loops, loop-avg-us, loop-max-us, ramps, and writes.
+        --log                       Print the latest log lines of pasvd, oldest first. pasvd has to
+                                    be started with --log-lines.
+    -l, --list                      List the sinks. Prints a line per sink with the tab-separated
+                                    index, name, volume in percents, and description.
+        --set-default [SINK]        Set the default sink and print whether it succeeded.
+        --crossfade                 With --set-default, fade the old default sink out while SINK
+                                    fades up to its volume.
+        --balance [BALANCE]         Set the left/right balance of the default sink, from -1 (left)
+                                    to 1 (right). 0 is centered. The sink has to be stereo.
+        --toggle-preset [A] [B]     Change the volume of the default sink to whichever of the
+                                    presets A and B of pasvd is further from its volume.
+        --mute                      Mute the default sink.
+        --unmute                    Unmute the default sink.
+        --no-blip                   With --mute or --unmute, don't dip the volume when pasvd is
+                                    started with --confirm-blip.
+    -c, --cancel                    Stop the running volume change. The volume stays where the
+                                    transition had reached; it isn't reset to the original value.
+        --envelope [OPTIONS]        Change the volume up to a peak and back, such as for alerts.
+                                    OPTIONS are `peak=<VOLUME> [attack=<MS>] [hold=<MS>]
+                                    [release=<MS>] [end=<VOLUME>]`, as one argument. The end
+                                    defaults to the current volume.
+        --sleep [MINUTES]           Fade the default sink out and mute it after MINUTES.
+        --cancel-sleep              Cancel the fade of --sleep.
     -d, --duration [MILLISECONDS]   Specifies the duration to smoothly change volume.
+        --from [VOLUME]             Set the volume to VOLUME first and change it from there,
+                                    instead of from the current volume. Both are absolute.
+    -e, --easing [EASING]           The curve of the volume change: linear, ease-in, ease-out,
+                                    ease-in-out, or ease-ends (linear in the middle, see the
+                                    daemon's --ease-fraction). Defaults to the daemon's --easing.
+        --gamma [G]                 Raise the progress of the volume change to the power G before
+                                    the easing. Defaults to the daemon's --gamma.
+        --steps [N]                 Change the volume over N of the daemon's intervals instead of
+                                    over a duration.
+    -w, --wait                      After changing the volume, wait until the daemon is idle.
+        --decimal-comma             Accept a comma as the decimal separator of the volume, such as
+                                    `0,5`.
+        --report-saturation         After changing the volume, print `SATURATED <VOLUME>` if the
+                                    target was clamped to the daemon's volume range.
+        --timeout [SECONDS]         How long --wait waits before failing. Defaults to 60.
+        --sink [NAME]               Change the volume of the named sink instead of the default.
+                                    `group:<GROUP>` changes the sinks of a group of pasvd.
+                                    With --source, NAME is the name of a source.
+        --app [NAME]                Change the volume of an application's stream instead, found
+                                    by its name (such as `Spotify`) or the index of the stream.
+                                    pasvd steps it like a sink, as PulseAudio has no volume ramp
+                                    for streams; its --max-writes-per-second writes less often.
+        --dry-run                   Print the command which would be sent to the daemon, such as
+                                    `+5% 150`, instead of connecting to it.
+        --generate-completions [SHELL]
+                                    Print the completion script of bash, zsh, or fish.
+        --no-wait                   Don't wait for the reply of the daemon, for the lowest latency.
+                                    Errors of the daemon aren't reported.
+
+Exit status:
+    0 on success, 1 on invalid arguments, 2 if the daemon replied with an error,
+    3 if connecting to the daemon failed, 4 if --wait timed out, and 5 if there is no
+    default sink or source.
         "
     );
     process::exit(1);
 }
+/// The daemon replied with an error.
+const EXIT_DAEMON_ERROR: i32 = 2;
+/// The daemon isn't reachable.
+const EXIT_CONNECT: i32 = 3;
+/// `--wait` timed out.
+const EXIT_TIMEOUT: i32 = 4;
+/// The daemon found no default sink or source, as opposed to failing to query it.
+const EXIT_NO_DEVICE: i32 = 5;
+/// The most decimals of `--precision`.
+const MAX_PRECISION: u8 = 6;
 fn arg_invalid_exit(s: impl AsRef<str>) -> ! {
     eprintln!("{}\nSee --help for usage information.", s.as_ref());
     process::exit(1);
 }
 
+/// Flags which send a fixed word to the daemon instead of a volume, by their long name.
+fn word_command(flag: &str) -> Option<&'static str> {
+    Some(match flag {
+        "get-volume" => "get-volume",
+        "status" => "status",
+        "list" => "list-sinks",
+        "mute" => "mute",
+        "unmute" => "unmute",
+        "cancel" => "cancel",
+        "cancel-sleep" => "cancel-sleep",
+        "watch" => "watch",
+        "log" => "log",
+        "info" => "info",
+        "metrics" => "metrics",
+        _ => return None,
+    })
+}
+/// Flags which send a word to the daemon followed by their value, by their long name.
+fn value_command(flag: &str) -> Option<&'static str> {
+    Some(match flag {
+        "set-default" => "set-default-sink",
+        "balance" => "balance",
+        "envelope" => "envelope",
+        "toggle-preset" => "toggle-preset",
+        "sleep" => "sleep",
+        _ => return None,
+    })
+}
+
 fn main() {
     let mut args = env::args();
     args.next();
 
     let mut volume = None;
     let mut path = None;
-    let mut get_volume = false;
-    let mut next_is_duration = false;
+    let mut command: Option<&'static str> = None;
+    let mut json = false;
+    let mut db = false;
+    let mut raw = false;
+    let mut channels = false;
+    let mut live = false;
+    let mut crossfade = false;
+    let mut no_blip = false;
+    let mut report = false;
+    let mut no_wait = false;
+    let mut dry_run = false;
+    let mut decimal_comma = false;
+    let mut source = false;
+    // the flag which takes the next argument as its value
+    let mut value_of: Option<&'static str> = None;
     let mut duration = None;
+    let mut sink = None;
+    let mut app = None;
+    // the value of `command`, sent after it
+    let mut command_value = None;
+    let mut steps = None;
+    let mut from = None;
+    let mut easing = None;
+    let mut gamma = None;
+    let mut precision = None;
+    let mut wait = false;
+    let mut timeout = Duration::from_secs(60);
 
     for arg in args {
-        if next_is_duration {
-            duration = Some(arg);
-            next_is_duration = false;
+        if let Some(flag) = value_of.take() {
+            match flag {
+                "duration" => duration = Some(arg),
+                "sink" => sink = Some(arg),
+                "app" => app = Some(arg),
+                "steps" => steps = Some(arg),
+                "from" => from = Some(arg),
+                "easing" => {
+                    // the daemon would take an unknown lone name for a duration
+                    if !completions::EASINGS.contains(&arg.as_str()) {
+                        arg_invalid_exit(format!("Unknown easing: {arg}."))
+                    }
+                    easing = Some(arg)
+                }
+                "gamma" => {
+                    // the daemon would take a bad one for a duration
+                    if !arg
+                        .parse()
+                        .is_ok_and(|gamma: f64| gamma.is_finite() && gamma > 0.)
+                    {
+                        arg_invalid_exit(format!(
+                            "Invalid gamma: {arg}, expected a positive number."
+                        ))
+                    }
+                    gamma = Some(arg)
+                }
+                "set-default" | "balance" | "envelope" => command_value = Some(arg),
+                "toggle-preset" => match &mut command_value {
+                    Some(a) => {
+                        a.push(' ');
+                        a.push_str(&arg);
+                    }
+                    None => {
+                        command_value = Some(arg);
+                        // and the second preset
+                        value_of = Some("toggle-preset");
+                    }
+                },
+                "sleep" => {
+                    let minutes: f64 = arg.parse().unwrap_or_else(|_| {
+                        arg_invalid_exit(format!("Invalid number of minutes: {arg}."))
+                    });
+                    command_value = Some((minutes * 60.).to_string());
+                }
+                "precision" => {
+                    let digits: u8 = arg
+                        .parse()
+                        .ok()
+                        .filter(|digits| *digits <= MAX_PRECISION)
+                        .unwrap_or_else(|| {
+                            arg_invalid_exit(format!(
+                                "Invalid precision: {arg}, expected 0 to {MAX_PRECISION} digits."
+                            ))
+                        });
+                    precision = Some(digits);
+                }
+                "generate-completions" => match completions::script(&arg) {
+                    Some(script) => {
+                        print!("{script}");
+                        return;
+                    }
+                    None => arg_invalid_exit(format!(
+                        "Unknown shell: {arg}, expected one of {}.",
+                        completions::SHELLS.join(", ")
+                    )),
+                },
+                "timeout" => {
+                    timeout = arg
+                        .parse()
+                        .ok()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                        .unwrap_or_else(|| arg_invalid_exit(format!("Invalid timeout: {arg}.")))
+                }
+                _ => unreachable!("--{flag} doesn't take a value"),
+            }
             continue;
         }
-        match arg.as_str() {
-            "--help" => print_help(),
-            "--get-volume" | "-g" => {
-                if path.is_some() {
+        let Some(&Flag(flag, _, takes_value)) = flags::find(&arg) else {
+            match arg.as_str() {
+                "-" if volume.is_none() => volume = Some(arg),
+                // `50 %` given as two arguments
+                "%" if command.is_none() && path.is_none() => match &mut volume {
+                    Some(volume) => volume.push('%'),
+                    None => arg_invalid_exit("Specify the volume before `%`."),
+                },
+                _ if arg.starts_with('-')
+                    // and not a number (negative numbers)
+                    && arg
+                        .strip_prefix('-')
+                        .unwrap()
+                        .chars()
+                        .next()
+                        .is_none_or(|c| !c.is_numeric() && c != '_' && c != '.') =>
+                {
+                    arg_invalid_exit(format!("Unrecognised argument: {arg}."))
+                }
+                _ if volume.is_some() && command.is_some() => {
                     arg_invalid_exit("Only one argument is valid.")
-                } else {
-                    get_volume = true
                 }
+                _ if volume.is_some() && path.is_some() => {
+                    arg_invalid_exit("Only two arguments are valid.")
+                }
+                _ if volume.is_some() => path = Some(arg),
+                _ => volume = Some(arg),
             }
-            "--duration" | "-d" => next_is_duration = true,
-            _ if arg.starts_with('-')
-                // and not a number (negative numbers)
-                && arg
-                    .strip_prefix('-')
-                    .unwrap()
-                    .chars()
-                    .next()
-                    .map_or(true, |c| !c.is_numeric() && c != '_' && c != '.') =>
-            {
-                arg_invalid_exit(format!("Unrecognised argument: {arg}."))
+            continue;
+        };
+        if takes_value {
+            if let Some(word) = value_command(flag) {
+                if path.is_some() || command.is_some() {
+                    arg_invalid_exit("Only one argument is valid.")
+                }
+                command = Some(word);
             }
-            _ if volume.is_some() && get_volume => arg_invalid_exit("Only one argument is valid."),
-            _ if volume.is_some() && path.is_some() => {
-                arg_invalid_exit("Only two arguments are valid.")
+            value_of = Some(flag);
+            continue;
+        }
+        match flag {
+            "help" => print_help(),
+            _ if word_command(flag).is_some() => {
+                if path.is_some() || command.is_some() {
+                    arg_invalid_exit("Only one argument is valid.")
+                } else {
+                    command = word_command(flag)
+                }
             }
-            _ if volume.is_some() => path = Some(arg),
-            _ => volume = Some(arg),
+            "json" => json = true,
+            "db" => db = true,
+            "raw" => raw = true,
+            "channels" => channels = true,
+            "live" => live = true,
+            "crossfade" => crossfade = true,
+            "no-blip" => no_blip = true,
+            "report-saturation" => report = true,
+            "decimal-comma" => decimal_comma = true,
+            "source" => source = true,
+            "wait" => wait = true,
+            "no-wait" => no_wait = true,
+            "dry-run" => dry_run = true,
+            _ => unreachable!("--{flag} isn't handled"),
+        }
+    }
+    if let Some(flag) = value_of {
+        if flag == "toggle-preset" {
+            arg_invalid_exit("--toggle-preset takes two presets.");
+        }
+        arg_invalid_exit(format!("--{flag} takes a value"));
+    }
+    if json {
+        if command != Some("get-volume") {
+            arg_invalid_exit("--json is only valid with --get-volume.");
         }
+        command = Some("get-volume-json");
+    }
+    if db {
+        if command != Some("get-volume") {
+            arg_invalid_exit("--db is only valid with --get-volume.");
+        }
+        command = Some("get-volume-db");
+    }
+    if raw {
+        if command != Some("get-volume") {
+            arg_invalid_exit("--raw is only valid with --get-volume.");
+        }
+        command = Some("get-volume-raw");
+    }
+    if channels {
+        if command != Some("get-volume") {
+            arg_invalid_exit("--channels is only valid with --get-volume.");
+        }
+        command = Some("get-volume-channels");
+    }
+    if live {
+        if !command.is_some_and(|command| command.starts_with("get-volume")) {
+            arg_invalid_exit("--live is only valid with --get-volume.");
+        }
+        command_value = Some("live".to_owned());
+    }
+    if let Some(precision) = precision {
+        if !matches!(command, Some("get-volume" | "get-volume-channels")) {
+            arg_invalid_exit(
+                "--precision is only valid with --get-volume, without --json, --db, or --raw.",
+            );
+        }
+        // before the `live` option
+        command_value = Some(match command_value {
+            Some(live) => format!("precision={precision} {live}"),
+            None => format!("precision={precision}"),
+        });
+    }
+    if crossfade {
+        if command != Some("set-default-sink") {
+            arg_invalid_exit("--crossfade is only valid with --set-default.");
+        }
+        command = Some("crossfade");
+    }
+    if no_blip {
+        if !matches!(command, Some("mute" | "unmute")) {
+            arg_invalid_exit("--no-blip is only valid with --mute or --unmute.");
+        }
+        command_value = Some("no-blip".to_owned());
+    }
+    if app.is_some() && (source || sink.is_some() || command.is_some()) {
+        arg_invalid_exit(
+            "--app is only valid when changing the volume, without --source or --sink.",
+        );
+    }
+    if (from.is_some() || easing.is_some() || gamma.is_some()) && command.is_some() {
+        arg_invalid_exit("--from, --easing, and --gamma are only valid when changing the volume.");
+    }
+    if report && command.is_some() {
+        arg_invalid_exit("--report-saturation is only valid when changing the volume.");
+    }
+    if wait && command.is_some() {
+        arg_invalid_exit("--wait is only valid when changing the volume.");
+    }
+    if no_wait && (wait || report || command.is_some()) {
+        arg_invalid_exit(
+            "--no-wait is only valid when changing the volume, without --wait or \
+            --report-saturation.",
+        );
+    }
+
+    if command.is_none() && volume.as_deref() == Some("-") {
+        let mut line = String::new();
+        let _ = io::stdin().read_line(&mut line);
+        volume = Some(line.trim().to_owned()).filter(|line| !line.is_empty());
     }
-    if next_is_duration {
-        arg_invalid_exit("--duration takes a value");
+    if command.is_none() {
+        volume = volume.map(|volume| normalize_volume(&volume, decimal_comma));
     }
 
-    let path = (if get_volume { &volume } else { &path })
+    let path = (if command.is_some() { &volume } else { &path })
         .as_ref()
         .map(std::path::PathBuf::from)
-        .unwrap_or_else(socket_path);
+        .unwrap_or_else(pasv_protocol::socket_path);
     let v = if let Some(v) = volume {
         v
-    } else if get_volume {
+    } else if command.is_some() {
         String::new()
     } else {
         arg_invalid_exit(
@@ -91,46 +442,197 @@ fn main() {
             and mean the same thing) as the first argument.",
         );
     };
-    let s = UnixStream::connect(&path);
-    let mut s = match s {
+    let prefix = if source { "source:" } else { "" };
+
+    let mut message = prefix.to_owned();
+    if let Some(command) = command {
+        message.push_str(command);
+        if let Some(value) = &command_value {
+            message.push(' ');
+            message.push_str(value);
+        }
+    } else {
+        if report {
+            message.push_str("report:");
+        }
+        if let Some(from) = &from {
+            message.push_str("fade ");
+            message.push_str(from);
+            message.push(' ');
+        }
+        if let Some(app) = &app {
+            message.push_str("app:");
+            message.push_str(app);
+            message.push(' ');
+        } else if let Some(sink) = &sink {
+            message.push_str(sink);
+            message.push(' ');
+        }
+        message.push_str(&v);
+        if let Some(steps) = &steps {
+            message.push_str(" steps=");
+            message.push_str(steps);
+        } else if let Some(duration) = &duration {
+            message.push(' ');
+            message.push_str(duration);
+        }
+        if let Some(easing) = &easing {
+            message.push(' ');
+            message.push_str(easing);
+        }
+        if let Some(gamma) = &gamma {
+            message.push_str(" gamma=");
+            message.push_str(gamma);
+        }
+    }
+    if dry_run {
+        println!("{message}");
+        return;
+    }
+    if command == Some("watch") {
+        if source {
+            arg_invalid_exit("--watch prints both sinks and sources, --source isn't valid.");
+        }
+        let mut s = connect(&path);
+        s.write_all(message.as_bytes()).unwrap();
+        s.shutdown(std::net::Shutdown::Write).unwrap();
+        for line in BufReader::new(s).lines() {
+            let Ok(line) = line else { break };
+            println!("{line}");
+        }
+        process::exit(EXIT_CONNECT);
+    }
+    if no_wait {
+        let mut s = connect(&path);
+        s.write_all(message.as_bytes()).unwrap();
+        return;
+    }
+    let buf = send(&path, &message);
+
+    if let Some(err) = buf.strip_prefix("ERR:") {
+        let err = err.trim();
+        let no_device = err
+            .strip_prefix(pasv_protocol::NO_DEVICE)
+            .and_then(|message| message.strip_prefix(": "));
+        match no_device {
+            Some(message) => {
+                eprintln!("{message}");
+                process::exit(EXIT_NO_DEVICE);
+            }
+            None => {
+                eprintln!("{err}");
+                process::exit(EXIT_DAEMON_ERROR);
+            }
+        }
+    }
+    if buf.starts_with("SATURATED") {
+        println!("{buf}");
+    }
+    if wait {
+        let start = Instant::now();
+        while send(&path, "status") != "idle" {
+            if start.elapsed() > timeout {
+                eprintln!("Timed out waiting for the volume change to finish.");
+                process::exit(EXIT_TIMEOUT);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+    if command == Some("log") && !buf.is_empty() {
+        println!("{buf}");
+    }
+    if let Some(
+        command @ ("get-volume"
+        | "get-volume-json"
+        | "get-volume-db"
+        | "get-volume-raw"
+        | "get-volume-channels"
+        | "status"
+        | "info"
+        | "metrics"
+        | "list-sinks"
+        | "set-default-sink"),
+    ) = command
+    {
+        if buf.is_empty() {
+            eprintln!("Failed to query {command}, pasvd closed the connection without a reply.");
+            process::exit(EXIT_DAEMON_ERROR);
+        } else {
+            println!("{buf}");
+        }
+    }
+}
+/// Removes the whitespace of a volume, so ` 50 % ` reads as `50%`, and makes a comma a
+/// decimal point if `decimal_comma`, so `0,5` reads as `0.5`.
+fn normalize_volume(volume: &str, decimal_comma: bool) -> String {
+    volume
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| if decimal_comma && c == ',' { '.' } else { c })
+        .collect()
+}
+/// Sends `message` to the daemon and returns its reply.
+fn send(path: &Path, message: &str) -> String {
+    let mut s = connect(path);
+    s.write_all(message.as_bytes()).unwrap();
+    s.flush().unwrap();
+    s.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut buf = String::new();
+    s.read_to_string(&mut buf).unwrap();
+    buf
+}
+fn connect(path: &Path) -> UnixStream {
+    match pasv_protocol::connect(path) {
         Ok(s) => s,
         Err(err) => {
             eprintln!(
                 "pasvd is maybe not running.\nFailed to connect to {}: {err}",
                 path.display()
             );
-            process::exit(1);
+            process::exit(EXIT_CONNECT);
         }
-    };
+    }
+}
 
-    if get_volume {
-        s.write_all(b"get-volume").unwrap();
-        s.flush().unwrap();
-        s.shutdown(std::net::Shutdown::Write).unwrap();
-        let mut buf = Vec::new();
-        s.read_to_end(&mut buf).unwrap();
-        if buf.is_empty() {
-            eprintln!("Failed to query volume");
-            process::exit(1);
-        } else {
-            std::io::stdout().write_all(&buf).unwrap();
-            std::io::stdout().write_all(b"\n").unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_whitespace() {
+        for volume in ["50%", "50 %", " 50% ", " 50 % "] {
+            assert_eq!(normalize_volume(volume, false), "50%");
         }
-    } else {
-        s.write_all(v.as_bytes()).unwrap();
-        if let Some(duration) = duration {
-            s.write_all(b" ").unwrap();
-            s.write_all(duration.as_bytes()).unwrap();
+        assert_eq!(normalize_volume("+ 5 %", false), "+5%");
+        assert_eq!(normalize_volume("-3 dB", false), "-3dB");
+    }
+
+    #[test]
+    fn volume_decimal_comma() {
+        assert_eq!(normalize_volume("0,5", true), "0.5");
+        assert_eq!(normalize_volume("+2,5 %", true), "+2.5%");
+        assert_eq!(normalize_volume("0,5", false), "0,5");
+    }
+
+    #[test]
+    fn flags_by_long_and_short_name() {
+        assert_eq!(flags::find("--duration").unwrap().0, "duration");
+        assert_eq!(flags::find("-d").unwrap().0, "duration");
+        assert_eq!(flags::find("-l").unwrap().0, "list");
+        // volumes and stdin
+        for arg in ["-5", "-0.5", "-", "-dd", "--nope"] {
+            assert!(flags::find(arg).is_none(), "{arg}");
         }
-    };
-}
-fn socket_path() -> std::path::PathBuf {
-    let mut p = Path::new("/run").to_path_buf();
-    let user: u32 = unsafe { libc::getuid() };
-    if user != 0 {
-        p.push("user");
-        p.push(user.to_string());
-    }
-    p.push("pasvd");
-    p
+        for Flag(flag, short, takes_value) in flags::FLAGS {
+            if word_command(flag).is_some() {
+                assert!(!takes_value, "{flag}");
+            }
+            if value_command(flag).is_some() {
+                assert!(takes_value, "{flag}");
+            }
+            if let Some(short) = short {
+                assert_eq!(flags::find(&format!("-{short}")).unwrap().0, *flag);
+            }
+        }
+    }
 }
@@ -0,0 +1,543 @@
+//! The sound servers [`VolumeController`](crate::VolumeController) talks to.
+//!
+//! [`PulseBackend`] speaks the PulseAudio client protocol, which PipeWire also serves through
+//! `pipewire-pulse`. Volumes are passed as libpulse [`ChannelVolumes`], which other backends
+//! convert to and from.
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+use libpulse_binding::callbacks::ListResult;
+use libpulse_binding::channelmap::Position;
+use libpulse_binding::context::subscribe::{
+    Facility, InterestMaskSet, Operation as SubscriptionOperation,
+};
+use libpulse_binding::context::{Context, FlagSet, State};
+use libpulse_binding::mainloop::threaded::Mainloop;
+use libpulse_binding::proplist::Proplist;
+use libpulse_binding::volume::ChannelVolumes;
+
+use crate::{vol_from_linear, vol_to_linear, Device, Message};
+
+/// The operations on a sound server the transitions need.
+///
+/// The `query_*` methods call their callback once, possibly from another thread, and the
+/// `set_*` methods don't wait for the server, except [`Self::set_default_sink`]. The others
+/// block until the server replies. Volume changes and the writes of the transitions only use
+/// the ones which don't, but rarer commands, such as muting, crossfades, and listing the
+/// sinks, delay the next write of running transitions by a round trip to the server.
+///
+/// Server events, such as changed default devices or a lost connection, are sent as
+/// [`Message`]s to the channel the backend was created with.
+pub trait VolumeBackend {
+    /// The state of the connection, for logging.
+    fn state(&self) -> String;
+    /// Whether the connection is gone, so it has to be [reconnected](Self::reconnect).
+    fn is_lost(&self) -> bool;
+    /// Connects to the server again, after [`Self::disconnect`].
+    fn reconnect(&mut self) -> Result<(), String>;
+    fn disconnect(&mut self);
+    /// Waits for the previous operations to finish.
+    fn sync(&self) {
+        self.get_default(Device::Sink);
+    }
+
+    /// Reads the default sink and source.
+    fn query_defaults(&self, callback: impl FnOnce(Option<String>, Option<String>) + 'static);
+    /// Reads the volume, description, and mute state of a device. [`None`] if the device
+    /// doesn't exist.
+    fn query_volume(
+        &self,
+        device: Device,
+        name: &str,
+        callback: impl FnOnce(Option<(ChannelVolumes, String, bool)>) + 'static,
+    );
+    /// Reads the name of the sink with `index`, if it exists.
+    fn query_sink_name(&self, index: u32, callback: impl FnOnce(Option<String>) + 'static);
+
+    fn get_default(&self, device: Device) -> Option<String> {
+        // streams have no default
+        if device == Device::SinkInput {
+            return None;
+        }
+        let (tx, rx) = mpsc::channel();
+        self.query_defaults(move |sink, source| {
+            let _ = tx.send(match device {
+                Device::Source => source,
+                _ => sink,
+            });
+        });
+        self.wait(&rx).flatten()
+    }
+    fn get_volume(&self, device: Device, name: &str) -> Option<(ChannelVolumes, String, bool)> {
+        let (tx, rx) = mpsc::channel();
+        self.query_volume(device, name, move |v| {
+            let _ = tx.send(v);
+        });
+        self.wait(&rx).flatten()
+    }
+    /// Waits for a reply of the server. [`None`] if it doesn't come, such as when a callback
+    /// is lost.
+    fn wait<T>(&self, rx: &mpsc::Receiver<T>) -> Option<T> {
+        rx.recv().ok()
+    }
+    /// Gets the label and volume of each channel of a device. [`None`] if the device doesn't
+    /// exist.
+    fn get_channels(&self, device: Device, name: &str) -> Option<Vec<(String, f64)>>;
+    /// Gets the index, name, description, and volume of all sinks.
+    fn list_sinks(&self) -> Vec<(u32, String, String, ChannelVolumes)>;
+    /// Finds the stream of the application with the `application.name` or index `app`.
+    fn find_sink_input(&self, app: &str) -> Option<u32>;
+
+    /// Sets the loudest channel of `name` to `vol`, scaling the others to keep the balance of
+    /// `channels`.
+    fn set_volume(&self, device: Device, name: &str, channels: &ChannelVolumes, vol: f64) {
+        let mut volume = *channels;
+        volume.scale(vol_from_linear(vol));
+        self.set_channels(device, name, &volume);
+    }
+    /// Sets the volume of each channel of `name`, without waiting for the server.
+    ///
+    /// If the server rejects it, [`Message::DeviceFailed`] is sent, which aborts the transition
+    /// of the device if it disappeared or keeps failing.
+    fn set_channels(&self, device: Device, name: &str, volume: &ChannelVolumes);
+    fn set_mute(&self, device: Device, name: &str, mute: bool);
+    /// Sets the default sink. Returns whether it succeeded.
+    fn set_default_sink(&mut self, name: &str) -> bool;
+}
+
+/// A connection to a PulseAudio server.
+pub struct PulseBackend {
+    // dropped before the mainloop it runs on
+    ctx: Context,
+    ml: Mainloop,
+    props: Proplist,
+    server: Option<String>,
+    /// How long [`VolumeBackend::wait`] waits for a reply.
+    query_timeout: Duration,
+    verbose: bool,
+    messages: mpsc::Sender<Message>,
+}
+impl PulseBackend {
+    /// Connects to `server`, or the default server if it's [`None`], which respects
+    /// `PULSE_SERVER`. Returns why if the connection fails.
+    ///
+    /// Queries which aren't answered within `query_timeout` fail, and are logged if `verbose`.
+    pub fn connect(
+        server: Option<String>,
+        query_timeout: Duration,
+        verbose: bool,
+        messages: mpsc::Sender<Message>,
+    ) -> Result<Self, String> {
+        let mut ml = Mainloop::new().expect("failed to create a libpulse Mainloop");
+
+        let mut props = Proplist::new().unwrap();
+        props
+            .set_str(
+                libpulse_binding::proplist::properties::APPLICATION_NAME,
+                "pa-smooth-volume",
+            )
+            .unwrap();
+
+        ml.start().unwrap();
+
+        let ctx = connect(&ml, &props, server.as_deref(), messages.clone())?;
+        Ok(Self {
+            ctx,
+            ml,
+            props,
+            server,
+            query_timeout,
+            verbose,
+            messages,
+        })
+    }
+    /// Issuing operations on a context which isn't ready panics, and their callbacks are never
+    /// called. Check this before waiting on a callback.
+    fn is_ready(&self) -> bool {
+        self.ctx.get_state() == State::Ready
+    }
+}
+impl VolumeBackend for PulseBackend {
+    fn state(&self) -> String {
+        format!("{:?}", self.ctx.get_state())
+    }
+    fn is_lost(&self) -> bool {
+        matches!(self.ctx.get_state(), State::Failed | State::Terminated)
+    }
+    fn reconnect(&mut self) -> Result<(), String> {
+        self.ctx = connect(
+            &self.ml,
+            &self.props,
+            self.server.as_deref(),
+            self.messages.clone(),
+        )?;
+        Ok(())
+    }
+    fn disconnect(&mut self) {
+        self.ctx.set_state_callback(None);
+        self.ctx.disconnect();
+    }
+    fn wait<T>(&self, rx: &mpsc::Receiver<T>) -> Option<T> {
+        match rx.recv_timeout(self.query_timeout) {
+            Ok(reply) => Some(reply),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if self.verbose {
+                    eprintln!(
+                        "PulseAudio didn't reply within {:?}, the device might have vanished.",
+                        self.query_timeout
+                    );
+                }
+                None
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    fn query_defaults(&self, callback: impl FnOnce(Option<String>, Option<String>) + 'static) {
+        if !self.is_ready() {
+            return callback(None, None);
+        }
+        let mut callback = Some(callback);
+        self.ctx.introspect().get_server_info(move |info| {
+            let name = |name: &Option<std::borrow::Cow<str>>| {
+                name.as_ref().map(|c| c.clone().into_owned())
+            };
+            if let Some(callback) = callback.take() {
+                callback(
+                    name(&info.default_sink_name),
+                    name(&info.default_source_name),
+                );
+            }
+        });
+    }
+    fn query_volume(
+        &self,
+        device: Device,
+        name: &str,
+        callback: impl FnOnce(Option<(ChannelVolumes, String, bool)>) + 'static,
+    ) {
+        if !self.is_ready() {
+            return callback(None);
+        }
+        let mut callback = Some(callback);
+        let mut found = None;
+        // called with each item, then once more at the end of the list
+        macro_rules! on_info {
+            ($info:ident => $description:expr) => {
+                move |info| match info {
+                    ListResult::Item($info) => {
+                        found = Some(($info.volume, $description, $info.mute))
+                    }
+                    ListResult::End | ListResult::Error => {
+                        if let Some(callback) = callback.take() {
+                            callback(found.take());
+                        }
+                    }
+                }
+            };
+        }
+        match device {
+            Device::Sink => {
+                self.ctx.introspect().get_sink_info_by_name(
+                    name,
+                    on_info!(info => info.description.as_deref().unwrap_or_default().to_owned()),
+                );
+            }
+            Device::Source => {
+                self.ctx.introspect().get_source_info_by_name(
+                    name,
+                    on_info!(info => info.description.as_deref().unwrap_or_default().to_owned()),
+                );
+            }
+            Device::SinkInput => {
+                let Ok(index) = name.parse() else {
+                    return callback.take().unwrap()(None);
+                };
+                // the application is the human-friendly name of its stream
+                self.ctx.introspect().get_sink_input_info(
+                    index,
+                    on_info!(info => info
+                        .proplist
+                        .get_str(libpulse_binding::proplist::properties::APPLICATION_NAME)
+                        .unwrap_or_default()),
+                );
+            }
+        }
+    }
+    fn query_sink_name(&self, index: u32, callback: impl FnOnce(Option<String>) + 'static) {
+        if !self.is_ready() {
+            return callback(None);
+        }
+        let mut callback = Some(callback);
+        let mut found = None;
+        self.ctx
+            .introspect()
+            .get_sink_info_by_index(index, move |info| match info {
+                ListResult::Item(info) => found = info.name.as_deref().map(str::to_owned),
+                ListResult::End | ListResult::Error => {
+                    if let Some(callback) = callback.take() {
+                        callback(found.take());
+                    }
+                }
+            });
+    }
+
+    fn get_channels(&self, device: Device, name: &str) -> Option<Vec<(String, f64)>> {
+        if !self.is_ready() {
+            return None;
+        }
+        let (tx, rx) = mpsc::channel();
+        macro_rules! on_info {
+            () => {
+                move |info| match info {
+                    ListResult::Item(info) => {
+                        let channels = info
+                            .channel_map
+                            .get()
+                            .iter()
+                            .zip(info.volume.get())
+                            .map(|(position, volume)| {
+                                (channel_label(*position), vol_to_linear(*volume))
+                            })
+                            .collect();
+                        let _ = tx.send(Some(channels));
+                    }
+                    ListResult::End | ListResult::Error => {
+                        let _ = tx.send(None);
+                    }
+                }
+            };
+        }
+        match device {
+            Device::Sink => {
+                self.ctx
+                    .introspect()
+                    .get_sink_info_by_name(name, on_info!());
+            }
+            Device::Source => {
+                self.ctx
+                    .introspect()
+                    .get_source_info_by_name(name, on_info!());
+            }
+            Device::SinkInput => {
+                let index = name.parse().ok()?;
+                self.ctx.introspect().get_sink_input_info(index, on_info!());
+            }
+        }
+        // the end of the list follows the item, if there is one
+        self.wait(&rx).flatten()
+    }
+    fn list_sinks(&self) -> Vec<(u32, String, String, ChannelVolumes)> {
+        if !self.is_ready() {
+            return Vec::new();
+        }
+        let (tx, rx) = mpsc::channel();
+        self.ctx.introspect().get_sink_info_list(move |info| {
+            // the receiver is gone if waiting timed out
+            if let ListResult::Item(info) = info {
+                let _ = tx.send(Some((
+                    info.index,
+                    info.name.as_deref().unwrap_or_default().to_owned(),
+                    info.description.as_deref().unwrap_or_default().to_owned(),
+                    info.volume,
+                )));
+            } else {
+                let _ = tx.send(None);
+            }
+        });
+        let mut sinks = Vec::new();
+        while let Some(Some(item)) = self.wait(&rx) {
+            sinks.push(item);
+        }
+        sinks
+    }
+    fn find_sink_input(&self, app: &str) -> Option<u32> {
+        if !self.is_ready() {
+            return None;
+        }
+        let (tx, rx) = mpsc::channel();
+        let app = app.to_owned();
+        self.ctx.introspect().get_sink_input_info_list(move |info| {
+            if let ListResult::Item(info) = info {
+                let matches = app.parse() == Ok(info.index)
+                    || info
+                        .proplist
+                        .get_str(libpulse_binding::proplist::properties::APPLICATION_NAME)
+                        .is_some_and(|name| name.eq_ignore_ascii_case(&app));
+                let _ = tx.send(Some(matches.then_some(info.index)));
+            } else {
+                let _ = tx.send(None);
+            }
+        });
+        let mut found = None;
+        while let Some(Some(item)) = self.wait(&rx) {
+            found = found.or(item);
+        }
+        found
+    }
+
+    fn set_channels(&self, device: Device, name: &str, volume: &ChannelVolumes) {
+        if !self.is_ready() {
+            return;
+        }
+        let messages = self.messages.clone();
+        let owned_name = name.to_owned();
+        let callback = Box::new(move |success: bool| {
+            if !success {
+                let _ = messages.send(Message::DeviceFailed(device, owned_name.clone()));
+            }
+        });
+        match device {
+            Device::Sink => {
+                self.ctx
+                    .introspect()
+                    .set_sink_volume_by_name(name, volume, Some(callback))
+            }
+            Device::Source => {
+                self.ctx
+                    .introspect()
+                    .set_source_volume_by_name(name, volume, Some(callback))
+            }
+            Device::SinkInput => {
+                let Ok(index) = name.parse() else {
+                    return;
+                };
+                self.ctx
+                    .introspect()
+                    .set_sink_input_volume(index, volume, Some(callback))
+            }
+        };
+    }
+    fn set_mute(&self, device: Device, name: &str, mute: bool) {
+        if !self.is_ready() {
+            return;
+        }
+        match device {
+            Device::Sink => self
+                .ctx
+                .introspect()
+                .set_sink_mute_by_name(name, mute, None),
+            Device::Source => self
+                .ctx
+                .introspect()
+                .set_source_mute_by_name(name, mute, None),
+            Device::SinkInput => {
+                let Ok(index) = name.parse() else {
+                    return;
+                };
+                self.ctx.introspect().set_sink_input_mute(index, mute, None)
+            }
+        };
+    }
+    fn set_default_sink(&mut self, name: &str) -> bool {
+        self.is_ready() && {
+            let (tx, rx) = mpsc::channel();
+            self.ctx.set_default_sink(name, move |success| {
+                let _ = tx.send(success);
+            });
+            self.wait(&rx).unwrap_or(false)
+        }
+    }
+}
+
+/// How long to wait for the server to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates a context and waits for it to be ready. Later state changes are sent as
+/// [`Message::StateChanged`] to `messages`.
+///
+/// Returns a readable error if the connection fails.
+fn connect(
+    ml: &Mainloop,
+    props: &Proplist,
+    server: Option<&str>,
+    messages: mpsc::Sender<Message>,
+) -> Result<Context, String> {
+    let mut ctx = Context::new_with_proplist(ml, "pa-smooth-volume", props)
+        .expect("failed to create a libpulse Context");
+    let (tx, rx) = mpsc::channel();
+
+    ctx.set_state_callback(Some(Box::new(move || {
+        let _ = tx.send(());
+    })));
+
+    let error = |err: String| match server {
+        Some(server) => format!("Failed to connect to the PulseAudio server `{server}`: {err}"),
+        None => format!("Failed to connect to PulseAudio: {err}"),
+    };
+    if let Err(err) = ctx.connect(server, FlagSet::NOFLAGS, None) {
+        return Err(error(format!("{err}")));
+    }
+
+    loop {
+        // wait for connection
+        if rx.recv_timeout(CONNECT_TIMEOUT).is_err() {
+            return Err(error("Timed out".to_owned()));
+        }
+        eprintln!("State change: {:?}", ctx.get_state());
+        match ctx.get_state() {
+            State::Ready => break,
+            State::Failed | State::Terminated => return Err(error(format!("{}", ctx.errno()))),
+            _ => {}
+        }
+    }
+    let sink_changes = messages.clone();
+    ctx.set_state_callback(Some(Box::new(move || {
+        let _ = messages.send(Message::StateChanged);
+    })));
+    ctx.set_subscribe_callback(Some(Box::new(move |facility, operation, index| {
+        // the default devices are part of the server info
+        let changed = match facility {
+            Some(Facility::Server) => true,
+            Some(Facility::Sink | Facility::Source) => {
+                matches!(
+                    operation,
+                    Some(SubscriptionOperation::New | SubscriptionOperation::Removed)
+                )
+            }
+            _ => false,
+        };
+        if changed {
+            let _ = sink_changes.send(Message::SinkChanged);
+        }
+        if facility == Some(Facility::Sink) && operation == Some(SubscriptionOperation::New) {
+            let _ = sink_changes.send(Message::SinkAdded(index));
+        }
+        if operation == Some(SubscriptionOperation::Changed) {
+            let device = match facility {
+                Some(Facility::Sink) => Some(Device::Sink),
+                Some(Facility::Source) => Some(Device::Source),
+                _ => None,
+            };
+            if let Some(device) = device {
+                let _ = sink_changes.send(Message::VolumeChanged(device));
+            }
+        }
+    })));
+    ctx.subscribe(
+        InterestMaskSet::SERVER | InterestMaskSet::SINK | InterestMaskSet::SOURCE,
+        |_| {},
+    );
+    Ok(ctx)
+}
+
+/// A short label of a channel, such as `L` for the front left one.
+fn channel_label(position: Position) -> String {
+    match position {
+        Position::Mono => "M",
+        Position::FrontLeft => "L",
+        Position::FrontRight => "R",
+        Position::FrontCenter => "C",
+        Position::RearLeft => "RL",
+        Position::RearRight => "RR",
+        Position::RearCenter => "RC",
+        Position::SideLeft => "SL",
+        Position::SideRight => "SR",
+        Position::Lfe => "LFE",
+        position => {
+            return Position::to_string(position).map_or_else(|| "?".to_owned(), Into::into)
+        }
+    }
+    .to_owned()
+}
@@ -0,0 +1,227 @@
+//! The configuration file, `$XDG_CONFIG_HOME/pasvd/config.toml`.
+//!
+//! The keys are the long names of the command-line options, such as `duration = 200` or
+//! `no-clamp = true`. Options given on the command line take precedence.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{env, fs, io};
+
+use clap::{ArgMatches, ValueSource};
+
+#[derive(Debug, Default)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+impl Config {
+    /// `$XDG_CONFIG_HOME/pasvd/config.toml`, falling back to `~/.config`.
+    pub fn path() -> Option<PathBuf> {
+        let mut p = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        p.push("pasvd");
+        p.push("config.toml");
+        Some(p)
+    }
+    /// Reads the config file. A missing file results in an empty config.
+    pub fn load() -> Result<Self, String> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        match fs::read_to_string(&path) {
+            Ok(s) => Self::parse(&s).map_err(|err| format!("{}: {err}", path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(format!("Failed to read {}: {err}", path.display())),
+        }
+    }
+    /// Parses `key = value` lines. Only the subset of TOML used for our options is supported:
+    /// bare keys, and values which are basic or literal strings or a single unquoted word, such
+    /// as a number or `true`. Anything else, such as tables and arrays, is an error.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        for (n, line) in s.lines().enumerate() {
+            let err = |err: &str| format!("line {}: {err}", n + 1);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                return Err(err("tables aren't supported, set the options at the top"));
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(err("expected `key = value`"));
+            };
+            let key = key.trim();
+            if key.is_empty()
+                || !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(err(&format!("unsupported key `{key}`")));
+            }
+            let (value, rest) = parse_value(value.trim_start()).map_err(|e| err(&e))?;
+            let rest = rest.trim_start();
+            if !rest.is_empty() && !rest.starts_with('#') {
+                return Err(err(&format!("unexpected `{rest}` after the value")));
+            }
+            if values.insert(key.to_owned(), value).is_some() {
+                return Err(err(&format!("`{key}` is set twice")));
+            }
+        }
+        Ok(Self { values })
+    }
+
+    /// Gets the option `id` from the command line if given there, otherwise from the config,
+    /// otherwise the default value of the option.
+    pub fn optional<T>(&self, matches: &ArgMatches, id: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr + Clone + Send + Sync + 'static,
+    {
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            if let Some(value) = self.values.get(id) {
+                return value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("Invalid value `{value}` for `{id}` in the config."));
+            }
+        }
+        Ok(matches.get_one::<T>(id).cloned())
+    }
+    /// Like [`Self::optional`], for options with a default value.
+    pub fn value<T>(&self, matches: &ArgMatches, id: &str) -> Result<T, String>
+    where
+        T: FromStr + Clone + Send + Sync + 'static,
+    {
+        self.optional(matches, id)
+            .map(|v| v.expect("option has a default value"))
+    }
+    /// Like [`Self::optional`], for options which can be given several times.
+    /// In the config, the values are separated by commas.
+    pub fn values(&self, matches: &ArgMatches, id: &str) -> Vec<String> {
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            if let Some(value) = self.values.get(id) {
+                return value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+            }
+        }
+        matches
+            .get_many::<String>(id)
+            .map_or_else(Vec::new, |values| values.cloned().collect())
+    }
+    /// Whether the flag `id` is given on the command line or set to `true` in the config.
+    pub fn flag(&self, matches: &ArgMatches, id: &str) -> Result<bool, String> {
+        if matches.contains_id(id) {
+            return Ok(true);
+        }
+        match self.values.get(id).map(String::as_str) {
+            None | Some("false") => Ok(false),
+            Some("true") => Ok(true),
+            Some(value) => Err(format!(
+                "Invalid value `{value}` for `{id}` in the config, expected `true` or `false`."
+            )),
+        }
+    }
+}
+
+/// Parses the value at the start of `s`, returning it and the rest of the line.
+fn parse_value(s: &str) -> Result<(String, &str), String> {
+    if s.starts_with("\"\"\"") || s.starts_with("'''") {
+        return Err("multi-line strings aren't supported".to_owned());
+    }
+    if let Some(literal) = s.strip_prefix('\'') {
+        let Some((string, rest)) = literal.split_once('\'') else {
+            return Err("unterminated string".to_owned());
+        };
+        return Ok((string.to_owned(), rest));
+    }
+    let Some(quoted) = s.strip_prefix('"') else {
+        let end = s.find(char::is_whitespace).unwrap_or(s.len());
+        let (word, rest) = s.split_at(end);
+        if word.is_empty() || word.contains(['"', '\'', '#', '[', ']', '{', '}', '\\']) {
+            return Err(format!(
+                "unsupported value `{word}`, quote it if it's a string"
+            ));
+        }
+        return Ok((word.to_owned(), rest));
+    };
+    let mut string = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &quoted[i + 1..])),
+            '\\' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('b') => '\u{8}',
+                    Some('t') => '\t',
+                    Some('n') => '\n',
+                    Some('f') => '\u{c}',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some(u @ ('u' | 'U')) => {
+                        let len = if u == 'u' { 4 } else { 8 };
+                        let hex: String = chars.by_ref().take(len).map(|(_, c)| c).collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| hex.len() == len)
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape `\\{u}{hex}`"))?
+                    }
+                    Some(c) => return Err(format!("unsupported escape `\\{c}`")),
+                    None => break,
+                };
+                string.push(escaped);
+            }
+            c => string.push(c),
+        }
+    }
+    Err("unterminated string".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_and_words() {
+        let config = Config::parse(
+            "# a comment\n\
+            duration = 200 # a trailing comment\n\
+            easing = \"ease-in\"\n\
+            server = 'tcp:host'\n\
+            group = \"room=a\\\\b+\\\"c\\\"\\u00e9\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.values["duration"], "200");
+        assert_eq!(config.values["easing"], "ease-in");
+        assert_eq!(config.values["server"], "tcp:host");
+        assert_eq!(config.values["group"], "room=a\\b+\"c\"\u{e9}");
+    }
+
+    #[test]
+    fn unsupported_syntax_is_an_error() {
+        for line in [
+            "[daemon]",
+            "preset = [\"a=0.5\"]",
+            "sink-max = { a = 0.5 }",
+            "path = /run/a#b",
+            "path = /run/a b",
+            "easing = \"linear\" \"ease-in\"",
+            "server = \"\\x41\"",
+            "server = \"\"\"a\"\"\"",
+            "server = \"a",
+            "daemon.duration = 200",
+            "\"duration\" = 200",
+            "duration =",
+            "duration = 200\nduration = 300",
+        ] {
+            assert!(Config::parse(line).is_err(), "{line}");
+        }
+    }
+}
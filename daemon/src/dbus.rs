@@ -0,0 +1,891 @@
+//! A minimal D-Bus client, enough to serve [`serve`]'s interface on the session bus and to show
+//! notifications.
+//!
+//! Only the basic types we use are implemented. Messages are sent little-endian, and received
+//! in either byte order.
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::{env, thread};
+
+use pa_smooth_volume::{ChangeVolume, Device, Message, VolumeKind};
+
+use crate::request;
+
+pub const NAME: &str = "dev.icelk.pasvd";
+pub const PATH: &str = "/dev/icelk/pasvd";
+pub const INTERFACE: &str = "dev.icelk.pasvd";
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+/// The longest message read. The specification allows 128 MiB, but the messages of this
+/// daemon are far shorter, so a longer one is refused instead of buffered.
+const MAX_MESSAGE_LEN: usize = 16 << 10;
+/// How long [`serve`] waits before connecting again after the connection failed. It doubles
+/// with each failed attempt, up to [`MAX_RECONNECT_DELAY`].
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="dev.icelk.pasvd">
+    <method name="ChangeVolume">
+      <arg name="volume" type="d" direction="in"/>
+      <arg name="duration" type="u" direction="in"/>
+    </method>
+    <method name="GetVolume">
+      <arg name="volume" type="d" direction="out"/>
+    </method>
+    <method name="SetMute">
+      <arg name="mute" type="b" direction="in"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Marshals values, aligned relative to the start of the buffer.
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+    /// Only for testing the [`Reader`], messages are sent little-endian.
+    big_endian: bool,
+}
+impl Writer {
+    fn align(&mut self, n: usize) {
+        while !self.buf.len().is_multiple_of(n) {
+            self.buf.push(0);
+        }
+    }
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+    pub fn u32(&mut self, v: u32) {
+        self.align(4);
+        let bytes = self.u32_bytes(v);
+        self.buf.extend_from_slice(&bytes);
+    }
+    fn u32_bytes(&self, v: u32) -> [u8; 4] {
+        if self.big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+    pub fn i32(&mut self, v: i32) {
+        self.u32(v as u32);
+    }
+    pub fn f64(&mut self, v: f64) {
+        self.align(8);
+        let bits = v.to_bits();
+        let bytes = if self.big_endian {
+            bits.to_be_bytes()
+        } else {
+            bits.to_le_bytes()
+        };
+        self.buf.extend_from_slice(&bytes);
+    }
+    pub fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+    pub fn signature(&mut self, s: &str) {
+        self.buf.push(s.len() as u8);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+    /// Writes an array whose elements have the alignment `element_align`.
+    pub fn array(&mut self, element_align: usize, elements: impl FnOnce(&mut Self)) {
+        self.u32(0);
+        let len_pos = self.buf.len() - 4;
+        self.align(element_align);
+        let start = self.buf.len();
+        elements(self);
+        let len = self.u32_bytes((self.buf.len() - start) as u32);
+        self.buf[len_pos..len_pos + 4].copy_from_slice(&len);
+    }
+    /// Starts a struct or dict entry.
+    pub fn structure(&mut self) {
+        self.align(8);
+    }
+}
+
+/// Unmarshals values, aligned relative to the start of the buffer.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+impl<'a> Reader<'a> {
+    /// Reads the little-endian `buf`.
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            big_endian: false,
+        }
+    }
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.div_ceil(n) * n;
+    }
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("D-Bus message is too short"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn u32(&mut self) -> io::Result<u32> {
+        self.align(4);
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u32()? != 0)
+    }
+    pub fn f64(&mut self) -> io::Result<f64> {
+        self.align(8);
+        let bytes = self.take(8)?.try_into().unwrap();
+        Ok(f64::from_bits(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        }))
+    }
+    pub fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let s = self.take(len + 1)?;
+        String::from_utf8(s[..len].to_vec()).map_err(|_| invalid("D-Bus string isn't UTF-8"))
+    }
+    pub fn signature(&mut self) -> io::Result<String> {
+        let len = self.u8()? as usize;
+        let s = self.take(len + 1)?;
+        String::from_utf8(s[..len].to_vec()).map_err(|_| invalid("D-Bus signature isn't UTF-8"))
+    }
+}
+
+/// A received message. Only the header fields we use are kept.
+#[derive(Debug, Default)]
+pub struct Received {
+    pub kind: u8,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub sender: Option<String>,
+    pub signature: String,
+    pub body: Vec<u8>,
+    pub big_endian: bool,
+}
+impl Received {
+    /// Reads the arguments in the body.
+    pub fn args(&self) -> Reader<'_> {
+        Reader {
+            big_endian: self.big_endian,
+            ..Reader::new(&self.body)
+        }
+    }
+}
+
+/// The contents of a message to send.
+#[derive(Debug, Default)]
+pub struct Outgoing<'a> {
+    pub kind: u8,
+    pub flags: u8,
+    pub path: Option<&'a str>,
+    pub interface: Option<&'a str>,
+    pub member: Option<&'a str>,
+    pub error_name: Option<&'a str>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<&'a str>,
+    pub signature: &'a str,
+    pub body: Writer,
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    stream: UnixStream,
+    serial: u32,
+}
+impl Connection {
+    /// Connects and authenticates to the session bus.
+    pub fn session() -> io::Result<Self> {
+        let address = env::var("DBUS_SESSION_BUS_ADDRESS").ok();
+        let stream = match address.as_deref().and_then(|a| a.strip_prefix("unix:")) {
+            Some(params) => {
+                let param = |key: &str| {
+                    params
+                        .split(',')
+                        .find_map(|p| p.strip_prefix(key)?.strip_prefix('='))
+                };
+                if let Some(path) = param("path") {
+                    UnixStream::connect(path)?
+                } else if let Some(name) = param("abstract") {
+                    use std::os::linux::net::SocketAddrExt;
+                    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                    UnixStream::connect_addr(&addr)?
+                } else {
+                    return Err(invalid("unsupported D-Bus session bus address"));
+                }
+            }
+            None => {
+                let dir = env::var_os("XDG_RUNTIME_DIR")
+                    .ok_or_else(|| invalid("no D-Bus session bus address"))?;
+                UnixStream::connect(PathBuf::from(dir).join("bus"))?
+            }
+        };
+        let mut conn = Self { stream, serial: 0 };
+        conn.authenticate()?;
+        conn.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            "",
+            Writer::default(),
+        )?;
+        Ok(conn)
+    }
+    fn authenticate(&mut self) -> io::Result<()> {
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{b:02x}")).collect();
+        self.stream.write_all(b"\0")?;
+        self.stream
+            .write_all(format!("AUTH EXTERNAL {hex}\r\n").as_bytes())?;
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        if !line.starts_with(b"OK ") {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "D-Bus authentication failed",
+            ));
+        }
+        self.stream.write_all(b"BEGIN\r\n")
+    }
+
+    /// Sends `msg`, returning its serial.
+    pub fn send(&mut self, msg: Outgoing) -> io::Result<u32> {
+        self.serial += 1;
+        self.stream.write_all(&encode(self.serial, &msg))?;
+        Ok(self.serial)
+    }
+    /// Receives the next message.
+    ///
+    /// The outer error is of the connection, which can't be used afterwards. The inner one is
+    /// of a malformed message, which was skipped.
+    pub fn receive(&mut self) -> io::Result<io::Result<Received>> {
+        let mut buf = vec![0; 16];
+        self.stream.read_exact(&mut buf)?;
+        let len = message_len(buf[..16].try_into().unwrap())?;
+        buf.resize(len, 0);
+        self.stream.read_exact(&mut buf[16..])?;
+        Ok(parse(buf))
+    }
+    /// Calls a method and waits for its reply. Other messages received meanwhile are dropped.
+    pub fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: Writer,
+    ) -> io::Result<Received> {
+        let serial = self.send(Outgoing {
+            kind: METHOD_CALL,
+            path: Some(path),
+            interface: Some(interface),
+            member: Some(member),
+            destination: Some(destination),
+            signature,
+            body,
+            ..Default::default()
+        })?;
+        loop {
+            let msg = match self.receive()? {
+                Ok(msg) if msg.reply_serial == Some(serial) => msg,
+                _ => continue,
+            };
+            return match msg.kind {
+                METHOD_RETURN => Ok(msg),
+                _ => Err(io::Error::other(format!(
+                    "D-Bus call {member} failed: {}",
+                    msg.error_name.as_deref().unwrap_or("unknown error")
+                ))),
+            };
+        }
+    }
+    fn reply(&mut self, to: &Received, signature: &str, body: Writer) -> io::Result<()> {
+        if to.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+        self.send(Outgoing {
+            kind: METHOD_RETURN,
+            reply_serial: Some(to.serial),
+            destination: to.sender.as_deref(),
+            signature,
+            body,
+            ..Default::default()
+        })
+        .map(|_| ())
+    }
+    fn reply_error(&mut self, to: &Received, name: &str, message: &str) -> io::Result<()> {
+        if to.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+        let mut body = Writer::default();
+        body.string(message);
+        self.send(Outgoing {
+            kind: ERROR,
+            error_name: Some(name),
+            reply_serial: Some(to.serial),
+            destination: to.sender.as_deref(),
+            signature: "s",
+            body,
+            ..Default::default()
+        })
+        .map(|_| ())
+    }
+}
+
+/// Marshals `msg` with the header.
+fn encode(serial: u32, msg: &Outgoing) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.u8(b'l');
+    w.u8(msg.kind);
+    w.u8(msg.flags);
+    w.u8(1);
+    w.u32(msg.body.buf.len() as u32);
+    w.u32(serial);
+    w.array(8, |w| {
+        let mut field = |code, signature: &str, value: &dyn Fn(&mut Writer)| {
+            w.structure();
+            w.u8(code);
+            w.signature(signature);
+            value(w);
+        };
+        if let Some(path) = msg.path {
+            field(1, "o", &|w| w.string(path));
+        }
+        if let Some(interface) = msg.interface {
+            field(2, "s", &|w| w.string(interface));
+        }
+        if let Some(member) = msg.member {
+            field(3, "s", &|w| w.string(member));
+        }
+        if let Some(error_name) = msg.error_name {
+            field(4, "s", &|w| w.string(error_name));
+        }
+        if let Some(reply_serial) = msg.reply_serial {
+            field(5, "u", &|w| w.u32(reply_serial));
+        }
+        if let Some(destination) = msg.destination {
+            field(6, "s", &|w| w.string(destination));
+        }
+        if !msg.signature.is_empty() {
+            field(8, "g", &|w| w.signature(msg.signature));
+        }
+    });
+    w.align(8);
+    w.buf.extend_from_slice(&msg.body.buf);
+    w.buf
+}
+/// The length of the whole message starting with the 16 bytes of `fixed`. An error means the
+/// start of the next message can't be found.
+fn message_len(fixed: &[u8; 16]) -> io::Result<usize> {
+    let mut r = Reader {
+        big_endian: match fixed[0] {
+            b'l' => false,
+            b'B' => true,
+            _ => return Err(invalid("invalid D-Bus byte order")),
+        },
+        ..Reader::new(fixed)
+    };
+    r.pos = 4;
+    let body_len = r.u32()? as usize;
+    r.pos = 12;
+    let fields_len = r.u32()? as usize;
+    let len = (16 + fields_len).div_ceil(8) * 8 + body_len;
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid("D-Bus message is too long"));
+    }
+    Ok(len)
+}
+/// Unmarshals the header of the message `buf`, of the length from [`message_len`].
+fn parse(mut buf: Vec<u8>) -> io::Result<Received> {
+    let fixed: &[u8; 16] = buf
+        .get(..16)
+        .and_then(|fixed| fixed.try_into().ok())
+        .ok_or_else(|| invalid("D-Bus message is too short"))?;
+    let len = message_len(fixed)?;
+    if buf.len() != len {
+        return Err(invalid("D-Bus message has the wrong length"));
+    }
+    let big_endian = fixed[0] == b'B';
+    let mut r = Reader {
+        big_endian,
+        ..Reader::new(&buf)
+    };
+    r.pos = 8;
+    let mut msg = Received {
+        kind: fixed[1],
+        flags: fixed[2],
+        serial: r.u32()?,
+        big_endian,
+        ..Default::default()
+    };
+    let fields_len = r.u32()? as usize;
+    let header_len = (16 + fields_len).div_ceil(8) * 8;
+    let mut r = Reader {
+        buf: &buf[..16 + fields_len],
+        ..r
+    };
+    while r.pos < 16 + fields_len {
+        r.align(8);
+        let code = r.u8()?;
+        let signature = r.signature()?;
+        match (code, signature.as_str()) {
+            (5, "u") => msg.reply_serial = Some(r.u32()?),
+            (8, "g") => msg.signature = r.signature()?,
+            (_, "s" | "o") => {
+                let s = Some(r.string()?);
+                match code {
+                    1 => msg.path = s,
+                    2 => msg.interface = s,
+                    3 => msg.member = s,
+                    4 => msg.error_name = s,
+                    7 => msg.sender = s,
+                    _ => {}
+                }
+            }
+            (_, "u") => {
+                r.u32()?;
+            }
+            (_, "g") => {
+                r.signature()?;
+            }
+            _ => return Err(invalid("unexpected D-Bus header field")),
+        }
+    }
+    msg.body = buf.split_off(header_len);
+    Ok(msg)
+}
+
+/// Registers [`NAME`] on the session bus and forwards method calls to `messages`, connecting
+/// again whenever the connection fails. Only returns if the name is taken by someone else.
+pub fn serve(messages: &mpsc::Sender<Message>) -> io::Error {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        let err = match register() {
+            Ok(mut conn) => {
+                delay = RECONNECT_DELAY;
+                dispatch(&mut conn, messages)
+            }
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => return err,
+            Err(err) => err,
+        };
+        eprintln!("The D-Bus connection failed, reconnecting in {delay:?}: {err}");
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+/// Connects to the session bus and requests [`NAME`].
+fn register() -> io::Result<Connection> {
+    let mut conn = Connection::session()?;
+    let mut body = Writer::default();
+    body.string(NAME);
+    // DBUS_NAME_FLAG_DO_NOT_QUEUE
+    body.u32(4);
+    let reply = conn.call(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        "org.freedesktop.DBus",
+        "RequestName",
+        "su",
+        body,
+    )?;
+    // DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    if reply.args().u32()? != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("the D-Bus name {NAME} is already taken"),
+        ));
+    }
+    Ok(conn)
+}
+/// Answers method calls until the connection fails, returning why.
+fn dispatch(conn: &mut Connection, messages: &mpsc::Sender<Message>) -> io::Error {
+    loop {
+        let msg = match conn.receive() {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(err)) => {
+                eprintln!("Skipping a malformed D-Bus message: {err}");
+                continue;
+            }
+            Err(err) => return err,
+        };
+        if let Err(err) = answer(conn, &msg, messages) {
+            return err;
+        }
+    }
+}
+/// Answers the method call `msg`. Errors are of the connection, malformed calls are replied
+/// to with an error.
+fn answer(
+    conn: &mut Connection,
+    msg: &Received,
+    messages: &mpsc::Sender<Message>,
+) -> io::Result<()> {
+    if msg.kind != METHOD_CALL {
+        return Ok(());
+    }
+    if msg.path.as_deref() != Some(PATH) {
+        return conn.reply_error(
+            msg,
+            "org.freedesktop.DBus.Error.UnknownObject",
+            "No such object.",
+        );
+    }
+    let mut args = msg.args();
+    let invalid_args = |conn: &mut Connection, err: io::Error| {
+        conn.reply_error(
+            msg,
+            "org.freedesktop.DBus.Error.InvalidArgs",
+            &err.to_string(),
+        )
+    };
+    match (
+        msg.interface.as_deref().unwrap_or(INTERFACE),
+        msg.member.as_deref().unwrap_or_default(),
+        msg.signature.as_str(),
+    ) {
+        ("org.freedesktop.DBus.Introspectable", "Introspect", "") => {
+            let mut body = Writer::default();
+            body.string(INTROSPECTION);
+            conn.reply(msg, "s", body)
+        }
+        (INTERFACE, "ChangeVolume", "du") => {
+            let (volume, duration) = match args.f64().and_then(|v| Ok((v, args.u32()?))) {
+                Ok(args) => args,
+                Err(err) => return invalid_args(conn, err),
+            };
+            let result = request(messages, |tx| Message::Change {
+                device: Device::Sink,
+                target: None,
+                volume: ChangeVolume::Absolute(volume),
+                // 0 uses the default duration
+                duration: (duration != 0).then_some(duration as f64),
+                steps: None,
+                from: None,
+                easing: None,
+                gamma: None,
+                reply: Some(tx),
+                saturated: None,
+            })
+            .and_then(|result| result);
+            reply_result(conn, msg, result)
+        }
+        (INTERFACE, "GetVolume", "") => {
+            match request(messages, |tx| {
+                Message::GetVolume(Device::Sink, VolumeKind::Intended, tx)
+            })
+            .and_then(|result| result)
+            {
+                Ok(info) => {
+                    let mut body = Writer::default();
+                    body.f64(info.volume);
+                    conn.reply(msg, "d", body)
+                }
+                Err(err) => conn.reply_error(msg, "dev.icelk.pasvd.Error.Failed", &err),
+            }
+        }
+        (INTERFACE, "SetMute", "b") => {
+            let mute = match args.bool() {
+                Ok(mute) => mute,
+                Err(err) => return invalid_args(conn, err),
+            };
+            let result = request(messages, |tx| {
+                Message::SetMute(Device::Sink, mute, true, tx)
+            })
+            .and_then(|result| result);
+            reply_result(conn, msg, result)
+        }
+        _ => conn.reply_error(
+            msg,
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            "No such method, or wrong arguments.",
+        ),
+    }
+}
+fn reply_result(
+    conn: &mut Connection,
+    msg: &Received,
+    result: Result<(), String>,
+) -> io::Result<()> {
+    match result {
+        Ok(()) => conn.reply(msg, "", Writer::default()),
+        Err(err) => conn.reply_error(msg, "dev.icelk.pasvd.Error.Failed", &err),
+    }
+}
+
+/// How long to wait for further transitions to finish before showing a notification.
+const NOTIFY_DEBOUNCE: Duration = Duration::from_millis(250);
+/// How long to wait for the notification daemon to answer, after which the next notification
+/// connects again.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a thread which shows a notification with the volume sent to the returned channel.
+///
+/// Successive volumes are debounced and update the same popup. If there's no notification
+/// daemon, nothing is shown. It uses the same [`Connection`] as [`serve`], so the replies it
+/// reads are limited to [`MAX_MESSAGE_LEN`] as well.
+pub fn notifier(verbose: bool) -> mpsc::Sender<(Device, f64)> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut conn = None;
+        // the notification to replace
+        let mut id = 0;
+        while let Ok(mut latest) = rx.recv() {
+            while let Ok(newer) = rx.recv_timeout(NOTIFY_DEBOUNCE) {
+                latest = newer;
+            }
+            let result = match conn.take() {
+                Some(conn) => Ok(conn),
+                None => Connection::session().and_then(|conn| {
+                    // a stuck server would otherwise hold up every later notification
+                    conn.stream.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+                    Ok(conn)
+                }),
+            }
+            .and_then(|mut conn| {
+                id = notify(&mut conn, id, latest)?;
+                Ok(conn)
+            });
+            match result {
+                Ok(c) => conn = Some(c),
+                Err(err) => {
+                    if verbose {
+                        eprintln!("Failed to show a notification: {err}");
+                    }
+                }
+            }
+        }
+    });
+    tx
+}
+/// Shows a notification, replacing the notification `id` if it isn't 0. Returns the ID of the
+/// new notification.
+fn notify(conn: &mut Connection, id: u32, (device, volume): (Device, f64)) -> io::Result<u32> {
+    let percent = (volume * 100.).round() as i32;
+    let level = match percent {
+        ..=0 => "muted",
+        1..=33 => "low",
+        34..=66 => "medium",
+        _ => "high",
+    };
+    let (summary, icon) = match device {
+        Device::Sink => ("Volume", format!("audio-volume-{level}")),
+        Device::Source => ("Microphone", format!("microphone-sensitivity-{level}")),
+        Device::SinkInput => ("Application volume", format!("audio-volume-{level}")),
+    };
+    let mut body = Writer::default();
+    body.string("pasvd");
+    body.u32(id);
+    body.string(&icon);
+    body.string(&format!("{summary} {percent}%"));
+    body.string("");
+    // actions
+    body.array(4, |_| {});
+    // hints, where `value` shows a progress bar
+    body.array(8, |w| {
+        w.structure();
+        w.string("value");
+        w.signature("i");
+        w.i32(percent.clamp(0, 100));
+    });
+    // the server's default expiration
+    body.i32(-1);
+    let reply = conn.call(
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        "org.freedesktop.Notifications",
+        "Notify",
+        "susssasa{sv}i",
+        body,
+    )?;
+    reply.args().u32()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        for big_endian in [false, true] {
+            let mut w = Writer {
+                big_endian,
+                ..Writer::default()
+            };
+            w.u8(7);
+            w.f64(0.25);
+            w.string("sink");
+            w.signature("du");
+            w.u32(u32::MAX);
+            w.array(8, |w| {
+                w.structure();
+                w.i32(-1);
+            });
+            let mut r = Reader {
+                big_endian,
+                ..Reader::new(&w.buf)
+            };
+            assert_eq!(r.u8().unwrap(), 7);
+            // aligned to 8
+            assert_eq!(r.f64().unwrap(), 0.25);
+            assert_eq!(r.pos, 16);
+            assert_eq!(r.string().unwrap(), "sink");
+            assert_eq!(r.signature().unwrap(), "du");
+            assert_eq!(r.u32().unwrap(), u32::MAX);
+            assert_eq!(r.u32().unwrap(), 4);
+            r.align(8);
+            assert_eq!(r.u32().unwrap() as i32, -1);
+            assert!(r.u8().is_err());
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut body = Writer::default();
+        body.f64(0.5);
+        body.u32(200);
+        let buf = encode(
+            3,
+            &Outgoing {
+                kind: METHOD_CALL,
+                flags: NO_REPLY_EXPECTED,
+                path: Some(PATH),
+                interface: Some(INTERFACE),
+                member: Some("ChangeVolume"),
+                error_name: Some("dev.icelk.pasvd.Error.Failed"),
+                reply_serial: Some(2),
+                destination: Some(NAME),
+                signature: "du",
+                body,
+            },
+        );
+        assert_eq!(
+            message_len(buf[..16].try_into().unwrap()).unwrap(),
+            buf.len()
+        );
+        let msg = parse(buf).unwrap();
+        assert_eq!(msg.kind, METHOD_CALL);
+        assert_eq!(msg.flags, NO_REPLY_EXPECTED);
+        assert_eq!(msg.serial, 3);
+        assert_eq!(msg.path.as_deref(), Some(PATH));
+        assert_eq!(msg.interface.as_deref(), Some(INTERFACE));
+        assert_eq!(msg.member.as_deref(), Some("ChangeVolume"));
+        assert_eq!(
+            msg.error_name.as_deref(),
+            Some("dev.icelk.pasvd.Error.Failed")
+        );
+        assert_eq!(msg.reply_serial, Some(2));
+        assert_eq!(msg.signature, "du");
+        let mut args = msg.args();
+        assert_eq!(args.f64().unwrap(), 0.5);
+        assert_eq!(args.u32().unwrap(), 200);
+    }
+
+    #[test]
+    fn big_endian_header() {
+        let mut w = Writer {
+            big_endian: true,
+            ..Writer::default()
+        };
+        for byte in [b'B', METHOD_CALL, 0, 1] {
+            w.u8(byte);
+        }
+        w.u32(8);
+        w.u32(9);
+        w.array(8, |w| {
+            w.structure();
+            w.u8(3);
+            w.signature("s");
+            w.string("SetMute");
+            w.structure();
+            w.u8(8);
+            w.signature("g");
+            w.signature("b");
+        });
+        w.align(8);
+        w.u32(1);
+        w.u32(0);
+        let msg = parse(w.buf).unwrap();
+        assert_eq!(msg.serial, 9);
+        assert_eq!(msg.member.as_deref(), Some("SetMute"));
+        assert_eq!(msg.signature, "b");
+        assert!(msg.args().bool().unwrap());
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let buf = encode(
+            1,
+            &Outgoing {
+                kind: METHOD_CALL,
+                member: Some("GetVolume"),
+                ..Outgoing::default()
+            },
+        );
+        // truncated
+        assert!(parse(buf[..buf.len() - 1].to_vec()).is_err());
+        assert!(parse(buf[..8].to_vec()).is_err());
+        // an unknown byte order loses the framing
+        let mut fixed: [u8; 16] = buf[..16].try_into().unwrap();
+        fixed[0] = b'x';
+        assert!(message_len(&fixed).is_err());
+        // longer than allowed
+        let mut fixed: [u8; 16] = buf[..16].try_into().unwrap();
+        fixed[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(message_len(&fixed).is_err());
+        // allowed by the specification, but not read
+        fixed[4..8].copy_from_slice(&(1u32 << 20).to_le_bytes());
+        assert!(message_len(&fixed).is_err());
+        // a header field of a type we don't read
+        let mut buf = buf;
+        let signature = buf.iter().position(|&b| b == b's').unwrap();
+        buf[signature] = b'v';
+        assert!(parse(buf).is_err());
+    }
+}
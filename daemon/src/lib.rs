@@ -0,0 +1,2481 @@
+//! Smoothly changes the volume of PulseAudio devices.
+//!
+//! [`VolumeController`] connects to PulseAudio and interpolates the volume. The `pasvd`
+//! binary wraps it with a socket for commands.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::{mem, thread};
+
+mod backend;
+pub use backend::{PulseBackend, VolumeBackend};
+
+use libpulse_binding::volume::{ChannelVolumes, Volume, VolumeDB};
+
+/// A change of the volume, relative to the current volume or absolute.
+#[derive(Debug, Clone, Copy)]
+pub enum ChangeVolume {
+    Increase(f64),
+    /// Multiply the volume, used by relative decibel changes.
+    Multiply(f64),
+    Absolute(f64),
+}
+impl ChangeVolume {
+    /// The volume after applying the change to `absolute_volume`.
+    pub fn collapse(self, absolute_volume: f64, mode: RelativeMode) -> f64 {
+        match (self, mode) {
+            (ChangeVolume::Increase(i), RelativeMode::Absolute) => absolute_volume + i,
+            (ChangeVolume::Increase(i), RelativeMode::Multiplicative) => absolute_volume * (1. + i),
+            (ChangeVolume::Multiply(m), _) => absolute_volume * m,
+            (ChangeVolume::Absolute(a), _) => a,
+        }
+    }
+}
+
+/// What [`ChangeVolume::Increase`] is relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeMode {
+    /// `+10%` adds 10% of the full volume.
+    Absolute,
+    /// `+10%` makes it 10% louder than the current volume. Silence stays silent.
+    Multiplicative,
+}
+impl RelativeMode {
+    pub const NAMES: [&'static str; 2] = ["absolute", "multiplicative"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "absolute" => Self::Absolute,
+            "multiplicative" => Self::Multiplicative,
+            _ => return None,
+        })
+    }
+}
+
+/// The kind of PulseAudio device a command acts on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Device {
+    Sink,
+    Source,
+    /// An application's stream to a sink. Its name is the index of the stream.
+    SinkInput,
+}
+impl std::fmt::Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Device::Sink => "sink",
+            Device::Source => "source",
+            Device::SinkInput => "sink-input",
+        })
+    }
+}
+
+/// Where the outcome of a command is sent. The error is a message for the user.
+pub type Reply = mpsc::SyncSender<Result<(), String>>;
+/// Where [`Message::Watch`] sends the device, its name, and its volume.
+pub type Watcher = mpsc::Sender<(Device, String, f64)>;
+/// A sink following the transition of its group, with its balance and maximum volume.
+type Follower = (String, ChannelVolumes, f64);
+
+/// A device whose transition finished, or which was set at once, from
+/// [`VolumeController::tick`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finished {
+    pub device: Device,
+    pub name: String,
+    /// The final volume, 0 if the device was muted at the end.
+    pub volume: f64,
+    /// Whether the device was muted at the end, such as by a fade to mute or the sleep timer.
+    /// The volume before is the level to come back to.
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Change {
+        device: Device,
+        /// The name of the device. `None` for the default.
+        target: Option<String>,
+        volume: ChangeVolume,
+        duration: Option<f64>,
+        /// The number of intervals the change takes, overriding the duration.
+        steps: Option<u32>,
+        /// The volume to start from, set immediately, instead of the current volume.
+        from: Option<f64>,
+        /// The curve to follow instead of [`Settings::easing`].
+        easing: Option<Easing>,
+        /// Overrides [`Settings::gamma`].
+        gamma: Option<f64>,
+        reply: Option<Reply>,
+        /// Receives the clamped target before the reply, if the target was outside
+        /// [`Settings::min_volume`] and [`Settings::max_volume`].
+        saturated: Option<mpsc::SyncSender<f64>>,
+    },
+    /// Replies with the volume of the device. During a transition, that's the last volume
+    /// written by it, unless the wanted [`VolumeKind`] is [`VolumeKind::Live`].
+    GetVolume(
+        Device,
+        VolumeKind,
+        mpsc::SyncSender<Result<VolumeInfo, String>>,
+    ),
+    /// Replies with the label and volume of each channel of the device as PulseAudio reports
+    /// it, such as `[("L", 0.45), ("R", 0.43)]`.
+    GetChannels(Device, mpsc::SyncSender<Result<Vec<(String, f64)>, String>>),
+    /// Replies with the volumes a transition from the first volume to the second would write,
+    /// each with the time since its start, without changing any device. The duration is in
+    /// milliseconds, like [`Message::Change`].
+    Preview {
+        from: f64,
+        to: f64,
+        duration: Option<f64>,
+        easing: Option<Easing>,
+        reply: mpsc::SyncSender<Result<Vec<(Duration, f64)>, String>>,
+    },
+    /// Replies with `idle` or a line per transition, `transitioning target=<f64> current=<f64>
+    /// <device>=<name>`, followed by ` description=<description>` if the device has one.
+    Status(mpsc::SyncSender<String>),
+    /// The `bool` is whether to dip the volume if [`Settings::confirm_blip`] is enabled.
+    Mute(Device, MuteAction, bool, Reply),
+    /// Mute (`true`) or unmute the device, then whether to dip the volume like
+    /// [`Message::Mute`].
+    SetMute(Device, bool, bool, Reply),
+    /// Set the balance of a stereo device, from -1 (left) to 1 (right), keeping the loudest
+    /// channel's volume.
+    Balance(Device, f64, Reply),
+    /// Replies with a line per sink of tab-separated index, name, volume, and description.
+    ListSinks(mpsc::SyncSender<String>),
+    /// Replies with whether the default sink was changed.
+    SetDefaultSink(String, mpsc::SyncSender<bool>),
+    /// Make the second sink the default, fading the first (or the default sink if [`None`]) to
+    /// mute while the second fades up from silence to its volume.
+    Crossfade(Option<String>, String, Reply),
+    /// Change the volume to whichever of the two presets is further from the volume, or from
+    /// the target of the running transition.
+    TogglePreset(Device, String, String, Reply),
+    /// Setting the volume of a device failed, possibly because it was removed.
+    DeviceFailed(Device, String),
+    /// Whether the device of a [`Message::DeviceFailed`] still exists was read.
+    DeviceChecked(Device, String, bool),
+    /// The default sink or source may have changed.
+    SinkChanged,
+    /// The volume of a device was read for a change. [`None`] if the device wasn't found.
+    VolumeRead(Box<PendingChange>, Option<(ChannelVolumes, String)>),
+    /// The balance of a sink of a group was read, before the [`Message::VolumeRead`] of the
+    /// change of the group.
+    BalanceRead(String, ChannelVolumes),
+    /// The default sink and source were read.
+    DefaultsRead(Option<String>, Option<String>),
+    /// The state of the PulseAudio connection changed.
+    StateChanged,
+    /// Stop the running transition. The volume is left where the ramp had reached.
+    Cancel,
+    /// Fade the default sink to mute after the duration, or cancel the fade if [`None`].
+    Sleep(Option<Duration>),
+    /// Replies with the kept log lines, oldest first, each prefixed with the time since the
+    /// start. See [`Settings::log_lines`].
+    Log(mpsc::SyncSender<String>),
+    /// Replies with counters since the start as `key=value` lines: `loops`, `loop-avg-us`,
+    /// `loop-max-us`, `ramps` (finished transitions), and `writes` (volumes written by them).
+    /// The loops are the ones passed to [`VolumeController::record_loop`].
+    Metrics(mpsc::SyncSender<String>),
+    /// Send the volume of the default sink, and then every volume written or changed by
+    /// others, until the receiver is dropped.
+    Watch(Watcher),
+    /// The volume of a device changed, possibly by another program.
+    VolumeChanged(Device),
+    /// Change the volume of the default device along the envelope. Replies when it starts.
+    Envelope(Device, Envelope, Reply),
+    /// A sink with the index appeared, such as after a suspend.
+    SinkAdded(u32),
+    /// Finish all transitions immediately and disconnect. See [`VolumeController::shutdown`].
+    Shutdown,
+}
+
+/// How long relative changes build on the unquantized target of the last change, instead of
+/// the volume read from PulseAudio.
+const INTENDED_WINDOW: Duration = Duration::from_secs(5);
+
+/// How long each half of the dip of [`Settings::confirm_blip`] takes.
+const BLIP_DURATION: Duration = Duration::from_millis(80);
+/// The share of the volume [`Settings::confirm_blip`] dips to.
+const BLIP_LEVEL: f64 = 0.3;
+
+/// How long the fade of [`Message::Sleep`] takes.
+const SLEEP_FADE_DURATION: Duration = Duration::from_secs(60);
+
+/// A shaped change, such as for alerts: up to `peak`, held there, and down to `end`.
+#[derive(Debug, Clone, Copy)]
+pub struct Envelope {
+    /// The duration to reach `peak` in milliseconds, or the default duration if [`None`].
+    pub attack: Option<f64>,
+    pub peak: f64,
+    /// How long to stay at `peak` in milliseconds.
+    pub hold: f64,
+    /// The duration from `peak` to `end` in milliseconds, or the default duration if [`None`].
+    pub release: Option<f64>,
+    /// The volume to end at. [`None`] returns to the volume before the envelope.
+    pub end: Option<f64>,
+}
+
+/// The latest change of a device waiting for its volume to be read, which expires after
+/// [`Settings::query_timeout`] if the read is lost.
+struct PendingRead {
+    id: u64,
+    sent: Instant,
+    // answered here, so a superseded or expired change is answered once
+    reply: Option<Reply>,
+}
+/// A [`Message::Change`] waiting for the volume of its device to be read.
+#[derive(Debug, Clone)]
+pub struct PendingChange {
+    id: u64,
+    key: (Device, String),
+    request: ChangeRequest,
+}
+/// The contents of a [`Message::Change`].
+#[derive(Debug, Clone)]
+struct ChangeRequest {
+    change: ChangeVolume,
+    duration: Option<f64>,
+    steps: Option<u32>,
+    from: Option<f64>,
+    easing: Option<Easing>,
+    gamma: Option<f64>,
+    /// Whether to fade to mute (`true`) or from mute (`false`).
+    fade: Option<bool>,
+    /// Mute the device when the target is reached, keeping the volume.
+    mute_after: bool,
+    reply: Option<Reply>,
+    saturated: Option<mpsc::SyncSender<f64>>,
+    /// The other sinks of the group the change is for, which follow the transition of its
+    /// first sink.
+    followers: Vec<String>,
+}
+impl ChangeRequest {
+    fn new(change: ChangeVolume) -> Self {
+        Self {
+            change,
+            duration: None,
+            steps: None,
+            from: None,
+            easing: None,
+            gamma: None,
+            fade: None,
+            mute_after: false,
+            reply: None,
+            saturated: None,
+            followers: Vec::new(),
+        }
+    }
+}
+
+/// The curve the volume follows during a transition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    /// Linear, except for smoothly speeding up and slowing down in the first and last
+    /// [`Settings::ease_fraction`] of the progress, where changes are most audible.
+    EaseEnds,
+}
+impl Easing {
+    pub const NAMES: [&'static str; 5] = pasv_protocol::EASINGS;
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "linear" => Self::Linear,
+            "ease-in" => Self::EaseIn,
+            "ease-out" => Self::EaseOut,
+            "ease-in-out" => Self::EaseInOut,
+            "ease-ends" => Self::EaseEnds,
+            _ => return None,
+        })
+    }
+    /// Maps the `progress` of a transition (in the range `0..=1`) to how far the volume has
+    /// come (also `0..=1`). `ease_fraction` is only used by [`Self::EaseEnds`].
+    pub fn apply(self, progress: f64, ease_fraction: f64) -> f64 {
+        match self {
+            Self::Linear => progress,
+            Self::EaseIn => progress * progress,
+            Self::EaseOut => 1. - (1. - progress) * (1. - progress),
+            Self::EaseInOut => {
+                if progress < 0.5 {
+                    2. * progress * progress
+                } else {
+                    1. - (2. - 2. * progress) * (2. - 2. * progress) / 2.
+                }
+            }
+            Self::EaseEnds => {
+                let fraction = ease_fraction;
+                if fraction <= 0. {
+                    return progress;
+                }
+                // the speed follows a smoothstep up to that of the middle, which makes up
+                // for the ends being covered at half of it
+                let speed = 1. / (1. - fraction);
+                // the distance covered after `t` of an end, integrating the smoothstep
+                let end = |t: f64| {
+                    let t = t / fraction;
+                    speed * fraction * (t * t * t - t * t * t * t / 2.)
+                };
+                if progress < fraction {
+                    end(progress)
+                } else if progress > 1. - fraction {
+                    1. - end(1. - progress)
+                } else {
+                    speed * (progress - fraction / 2.)
+                }
+            }
+        }
+    }
+}
+
+/// The length of a step of `steps=N` changes when [`Settings::interval`] is zero, as there's
+/// no interval to count in.
+pub const ZERO_INTERVAL_STEP: Duration = Duration::from_millis(1);
+/// The most points [`Message::Preview`] replies with.
+const MAX_PREVIEW_POINTS: u128 = 100_000;
+/// How many volume writes of a transition can fail before it's aborted.
+const MAX_WRITE_FAILURES: u32 = 5;
+/// How long to wait before the first retry of [`Settings::connect_retries`]. It doubles with
+/// each retry, up to [`MAX_CONNECT_RETRY_DELAY`].
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// How long to wait between attempts to reconnect after the connection is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The lowest level of perceptual transitions, in decibels.
+const PERCEPTUAL_FLOOR_DB: f64 = -120.;
+
+/// Interpolates from `a` at 0 to `b` at 1, which it reaches exactly, unlike
+/// `a + (b - a) * progress`.
+fn lerp(a: f64, b: f64, progress: f64) -> f64 {
+    a * (1. - progress) + b * progress
+}
+
+/// The counters of [`Message::Metrics`].
+#[derive(Debug, Default, Clone, Copy)]
+struct Metrics {
+    loops: u64,
+    loop_total: Duration,
+    loop_max: Duration,
+    ramps: u64,
+    writes: u64,
+}
+
+/// A transition of a single device's volume.
+///
+/// This is stepped by [`VolumeController::tick`] for every kind of [`Device`]. The volume ramps
+/// of PulseAudio are internal to the server, and the client API (`pa_context_set_*_volume`)
+/// only sets a volume immediately, so there is no native ramp to hand sink inputs to.
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    initial: f64,
+    target: f64,
+    /// When the transition started. The progress follows the clock, so slow ticks don't
+    /// stretch the transition.
+    start: Instant,
+    /// How long it takes from `initial` to `target`.
+    duration: Duration,
+    /// The progress last written by [`Self::next`], from 0 to 1.
+    progress: f64,
+    easing: Easing,
+    /// The power of the progress before easing, see [`Settings::gamma`].
+    gamma: f64,
+    /// See [`Settings::ease_fraction`].
+    ease_fraction: f64,
+    /// Interpolate in decibels instead of linearly.
+    perceptual: bool,
+    /// The transition skips the volumes below this, see [`Settings::fade_floor`].
+    floor: f64,
+    /// The balance of the channels, scaled to the interpolated volume.
+    channels: ChannelVolumes,
+    /// Mute the device when the target is reached.
+    mute_after: bool,
+    /// How many volume writes PulseAudio rejected.
+    failures: u32,
+    /// When the volume was last written, for [`Settings::max_writes_per_second`].
+    written: Option<Instant>,
+}
+impl Ramp {
+    fn at(&self, progress: f64) -> f64 {
+        let progress = self
+            .easing
+            .apply(progress.powf(self.gamma), self.ease_fraction);
+        let (mut initial, mut target) = (self.initial, self.target);
+        // the target itself is still set when the transition ends
+        if initial.max(target) > self.floor {
+            initial = initial.max(self.floor);
+            target = target.max(self.floor);
+        }
+        if self.perceptual {
+            // -inf isn't interpolable, so start from a practically inaudible level
+            let initial = vol_to_db(initial).max(PERCEPTUAL_FLOOR_DB);
+            let target = vol_to_db(target).max(PERCEPTUAL_FLOOR_DB);
+            let db = lerp(initial, target, progress);
+            if db <= PERCEPTUAL_FLOOR_DB {
+                0.
+            } else {
+                vol_from_db(db)
+            }
+        } else {
+            lerp(initial, target, progress)
+        }
+    }
+    /// The volume last written by [`Self::next`].
+    fn current(&self) -> f64 {
+        self.at(self.progress)
+    }
+    /// Advances to the current time. Returns the volume to set and whether the target is
+    /// reached.
+    fn next(&mut self) -> (f64, bool) {
+        self.progress = if self.duration.is_zero() {
+            1.
+        } else {
+            (self.start.elapsed().as_secs_f64() / self.duration.as_secs_f64()).min(1.)
+        };
+        let done = self.progress >= 1.;
+        (if done { self.target } else { self.current() }, done)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// The volume of a device.
+pub struct VolumeInfo {
+    pub volume: f64,
+    /// The PulseAudio volume of the loudest channel, without the rounding of `volume`.
+    pub raw: u32,
+    pub muted: bool,
+    pub device: Device,
+    pub name: String,
+    /// The human-friendly name, such as `Built-in Audio Analog Stereo`. Empty if there's none.
+    pub description: String,
+}
+impl VolumeInfo {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"volume\":{},\"muted\":{},\"{}\":\"{}\",\"description\":\"{}\"}}",
+            self.volume,
+            self.muted,
+            self.device,
+            json_escape(&self.name),
+            json_escape(&self.description)
+        )
+    }
+}
+
+/// Which volume [`Message::GetVolume`] replies with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeKind {
+    /// The volume the transition has reached, which follows the curve smoothly.
+    Intended,
+    /// The volume reported by PulseAudio.
+    Live,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MuteAction {
+    Toggle,
+    /// Ramp the volume down and then mute (`true`), or unmute and ramp back up (`false`).
+    Fade(bool),
+}
+
+/// How [`VolumeController`] changes the volume.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// The duration of volume changes which don't specify one.
+    pub duration: Duration,
+    /// Overrides [`Self::duration`] for changes which raise the volume.
+    pub duration_up: Option<Duration>,
+    /// Overrides [`Self::duration`] for changes which lower the volume.
+    pub duration_down: Option<Duration>,
+    /// The time between volume writes. [`VolumeController::tick`] should be called this often.
+    ///
+    /// Zero writes as fast as [`VolumeController::tick`] is called. Transitions still take
+    /// their duration, and `steps` count in [`ZERO_INTERVAL_STEP`]s.
+    pub interval: Duration,
+    /// The lowest volume changes go to. Fades to mute still go to zero.
+    pub min_volume: f64,
+    /// The highest volume changes go to. Relative changes saturate here.
+    pub max_volume: f64,
+    /// Lower maximums of sinks by name, such as speakers which distort when loud.
+    pub sink_max: HashMap<String, f64>,
+    /// Sinks changed together, by the group name. Changes of the sink `group:<NAME>` go to all
+    /// of them, with the same volume.
+    pub groups: HashMap<String, Vec<String>>,
+    /// Named volumes, which [`Message::TogglePreset`] changes between.
+    pub presets: HashMap<String, f64>,
+    pub easing: Easing,
+    /// The progress of transitions is raised to this power before the easing. Above 1 starts
+    /// slower and below 1 faster. Has to be positive.
+    pub gamma: f64,
+    /// How much of the progress at each end [`Easing::EaseEnds`] eases, from 0 (linear) to 0.5
+    /// (the same as [`Easing::EaseInOut`] in shape).
+    pub ease_fraction: f64,
+    pub relative_mode: RelativeMode,
+    /// Interpolate in decibels instead of linearly.
+    pub perceptual: bool,
+    /// Changes smaller than this are set at once instead of transitioning.
+    pub snap_threshold: f64,
+    /// Transitions jump over the volumes below this, which are practically inaudible, to not
+    /// spend their time there. Lowering the volume to below it ends the transition at the
+    /// floor before setting the target, which [`Self::mute_at_zero`] then mutes if it's zero.
+    /// Raising one from below it starts it at the floor. Volumes set at once aren't affected.
+    pub fade_floor: f64,
+    /// Relative changes of a device arriving this soon after its last change are added to
+    /// the target of its running transition, which continues from its current volume.
+    /// Zero restarts the transition for every change.
+    pub coalesce_window: Duration,
+    /// The most volume writes of a transition per second. The steps in between are skipped,
+    /// but the target is always written. Zero is unlimited.
+    pub max_writes_per_second: u32,
+    /// Mute devices whose transition ends at silence, and unmute them when a later change
+    /// raises the volume, so amplifiers don't hum at 0%.
+    ///
+    /// Devices muted by a fade to mute stay muted until they're unmuted.
+    pub mute_at_zero: bool,
+    /// Dip the volume briefly before muting and after unmuting, as an audible cue. Fades to
+    /// or from mute are already heard, so they don't dip.
+    pub confirm_blip: bool,
+    /// Change the volume of sinks which reappear, such as after a suspend, back to the target
+    /// of their last change.
+    pub restore_on_resume: bool,
+    /// Wait for the running transition of a device to finish before starting the next one,
+    /// instead of replacing it. A queued change is replied to when it starts.
+    pub queue: bool,
+    /// Print more information of the PulseAudio operations.
+    pub verbose: bool,
+    /// How many of the latest log lines to keep for [`Message::Log`], including the ones of
+    /// [`Self::verbose`] when it's off. Zero keeps none.
+    pub log_lines: usize,
+    /// How many more times [`VolumeController::new`] tries to connect if the first attempt
+    /// fails, waiting longer between each.
+    pub connect_retries: u32,
+    /// How long to wait for PulseAudio to answer a query, after which the device is treated
+    /// as missing instead of blocking the main loop, and a change waiting for the volume of
+    /// its device fails.
+    pub query_timeout: Duration,
+    /// The PulseAudio server to connect to, such as `tcp:host`.
+    /// [`None`] uses the default, which respects `PULSE_SERVER`.
+    pub server: Option<String>,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_millis(150),
+            duration_up: None,
+            duration_down: None,
+            interval: Duration::from_millis(10),
+            min_volume: 0.,
+            max_volume: 1.,
+            sink_max: HashMap::new(),
+            groups: HashMap::new(),
+            presets: HashMap::new(),
+            easing: Easing::Linear,
+            gamma: 1.,
+            ease_fraction: 0.1,
+            relative_mode: RelativeMode::Absolute,
+            perceptual: false,
+            snap_threshold: 0.005,
+            fade_floor: 0.,
+            coalesce_window: Duration::ZERO,
+            max_writes_per_second: 0,
+            mute_at_zero: false,
+            confirm_blip: false,
+            restore_on_resume: false,
+            queue: false,
+            verbose: false,
+            log_lines: 0,
+            connect_retries: 0,
+            query_timeout: Duration::from_secs(2),
+            server: None,
+        }
+    }
+}
+
+/// Logs a line of [`Settings::verbose`] output, formatted like [`eprintln!`].
+macro_rules! log {
+    ($controller:expr, $($arg:tt)*) => {
+        if $controller.settings.verbose || $controller.settings.log_lines > 0 {
+            $controller.log(format!($($arg)*), false);
+        }
+    };
+}
+
+/// Owns the PulseAudio connection and the running volume transitions.
+///
+/// Each device has its own transition, so changes of different sinks run at the same time,
+/// while a change of a device with a running transition replaces it (or waits for it, with
+/// [`Settings::queue`]).
+///
+/// PulseAudio events are sent as [`Message`]s to the channel given to [`Self::new`]. Receive
+/// them and pass them to [`Self::handle`], together with your own commands, and call
+/// [`Self::tick`] every [`Settings::interval`] while [`Self::is_idle`] is `false`.
+///
+/// The sound server is only accessed through the [`VolumeBackend`], [`PulseBackend`] by
+/// default. Use [`Self::with_backend`] for another one.
+pub struct VolumeController<B: VolumeBackend = PulseBackend> {
+    backend: B,
+    messages: mpsc::Sender<Message>,
+    settings: Settings,
+    // in-flight transitions of each device
+    ramps: HashMap<(Device, String), Ramp>,
+    // the channel balance of the last ramp of each device, since all channels read the same at zero
+    balances: HashMap<(Device, String), ChannelVolumes>,
+    // the level before `fade-mute`, restored by `fade-unmute`
+    pre_fade_volumes: HashMap<(Device, String), f64>,
+    // default device names, cleared when PulseAudio reports a change
+    defaults: HashMap<Device, Option<String>>,
+    // changes waiting for the transition of their device to finish, with `Settings::queue`
+    queue: VecDeque<((Device, String), Message)>,
+    // the latest change of each device waiting for its volume to be read
+    pending: HashMap<(Device, String), PendingRead>,
+    next_id: u64,
+    // when to fade to mute, from `Message::Sleep`
+    sleep_deadline: Option<Instant>,
+    // when to try to reconnect, while the connection is lost
+    reconnect_at: Option<Instant>,
+    // from `Message::Watch`
+    watchers: Vec<Watcher>,
+    // from `Self::record`
+    recorder: Option<Watcher>,
+    // changes set at once since the last `Self::tick`, see `Settings::snap_threshold`
+    snapped: Vec<Finished>,
+    // devices muted for reaching silence, see `Settings::mute_at_zero`
+    zero_muted: HashSet<(Device, String)>,
+    // the unquantized target of the last change of each device, and when it was reached
+    intended: HashMap<(Device, String), (f64, Instant)>,
+    // the changes after the running one of each device's `Message::Envelope`
+    envelopes: HashMap<(Device, String), VecDeque<ChangeRequest>>,
+    // the human-friendly names of devices, read with their volume
+    descriptions: HashMap<(Device, String), String>,
+    // the other sinks of the group of a running transition
+    followers: HashMap<(Device, String), Vec<Follower>>,
+    // the latest log lines, up to `Settings::log_lines`
+    log: VecDeque<String>,
+    started: Instant,
+    metrics: Metrics,
+}
+impl VolumeController {
+    /// Connects to PulseAudio. Returns why if the connection fails.
+    pub fn new(settings: Settings, messages: mpsc::Sender<Message>) -> Result<Self, String> {
+        let mut delay = CONNECT_RETRY_DELAY;
+        let mut attempt = 0;
+        // the server might not be up yet, such as at boot
+        let backend = loop {
+            match PulseBackend::connect(
+                settings.server.clone(),
+                settings.query_timeout,
+                settings.verbose,
+                messages.clone(),
+            ) {
+                Ok(backend) => break backend,
+                Err(err) if attempt < settings.connect_retries => {
+                    attempt += 1;
+                    if settings.verbose {
+                        eprintln!(
+                            "{err}. Retrying in {delay:?} ({attempt}/{}).",
+                            settings.connect_retries
+                        );
+                    }
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_CONNECT_RETRY_DELAY);
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        eprintln!("Connected");
+        Ok(Self::with_backend(backend, settings, messages))
+    }
+}
+impl<B: VolumeBackend> VolumeController<B> {
+    /// Uses the connected `backend`, which sends its events to `messages`.
+    pub fn with_backend(backend: B, settings: Settings, messages: mpsc::Sender<Message>) -> Self {
+        let mut controller = Self {
+            backend,
+            messages,
+            settings,
+            ramps: HashMap::new(),
+            balances: HashMap::new(),
+            pre_fade_volumes: HashMap::new(),
+            defaults: HashMap::new(),
+            queue: VecDeque::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+            sleep_deadline: None,
+            reconnect_at: None,
+            watchers: Vec::new(),
+            recorder: None,
+            snapped: Vec::new(),
+            zero_muted: HashSet::new(),
+            intended: HashMap::new(),
+            envelopes: HashMap::new(),
+            descriptions: HashMap::new(),
+            followers: HashMap::new(),
+            log: VecDeque::new(),
+            started: Instant::now(),
+            metrics: Metrics::default(),
+        };
+        let sink = controller.backend.get_default(Device::Sink);
+        eprintln!("Got sink.");
+        controller.defaults.insert(Device::Sink, sink);
+        controller
+    }
+    /// Prints `line` to stderr if [`Settings::verbose`] or it's an `error`, and keeps it for
+    /// [`Message::Log`]. Stdout is left to the replies of `--stdin`.
+    fn log(&mut self, line: String, error: bool) {
+        if error || self.settings.verbose {
+            eprintln!("{line}");
+        }
+        if self.settings.log_lines == 0 {
+            return;
+        }
+        if self.log.len() >= self.settings.log_lines {
+            self.log.pop_front();
+        }
+        let elapsed = self.started.elapsed().as_secs_f64();
+        self.log.push_back(format!("[{elapsed:.3}] {line}"));
+    }
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+    /// Sends each volume written to the backend to `recorder`, with the device and its name,
+    /// until the receiver is dropped or [`Self::shutdown`]. Unlike [`Message::Watch`], changes
+    /// by others aren't sent.
+    pub fn record(&mut self, recorder: Watcher) {
+        self.recorder = Some(recorder);
+    }
+    fn set_volume(&mut self, device: Device, name: &str, channels: &ChannelVolumes, volume: f64) {
+        set_volume(
+            &self.backend,
+            &mut self.recorder,
+            device,
+            name,
+            channels,
+            volume,
+        );
+    }
+    /// Raises [`Settings::interval`] to twice the typical time PulseAudio takes to set the
+    /// volume, measured by setting the default sink to its current volume a few times, so
+    /// slow servers aren't written to faster than they respond. Returns the new interval.
+    pub fn tune_interval(&mut self) -> Duration {
+        const PROBES: usize = 5;
+        let mut latencies = Vec::with_capacity(PROBES);
+        if let Some(name) = self.cached_default(Device::Sink) {
+            for _ in 0..PROBES {
+                let start = Instant::now();
+                let Some((v, _, _)) = self.backend.get_volume(Device::Sink, &name) else {
+                    break;
+                };
+                self.backend.set_channels(Device::Sink, &name, &v);
+                self.backend.sync();
+                latencies.push(start.elapsed());
+            }
+        }
+        latencies.sort_unstable();
+        if let Some(median) = latencies.get(latencies.len() / 2) {
+            self.settings.interval = self.settings.interval.max(*median * 2);
+            log!(
+                self,
+                "Setting the volume takes {median:?}, using an interval of {:?}",
+                self.settings.interval
+            );
+        }
+        self.settings.interval
+    }
+    /// Adds the duration of an iteration of the main loop to [`Message::Metrics`].
+    pub fn record_loop(&mut self, duration: Duration) {
+        let metrics = &mut self.metrics;
+        metrics.loops += 1;
+        metrics.loop_total += duration;
+        metrics.loop_max = metrics.loop_max.max(duration);
+    }
+    /// Whether no transitions are running or waiting for the volume of their device, so
+    /// [`Self::tick`] doesn't have to be called until [`Self::deadline`].
+    pub fn is_idle(&self) -> bool {
+        self.ramps.is_empty() && self.pending.is_empty()
+    }
+    /// When [`Self::tick`] has to be called even if idle: when the sleep timer starts fading
+    /// out or when to try to reconnect, whichever is first.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.sleep_deadline
+            .into_iter()
+            .chain(self.reconnect_at)
+            .min()
+    }
+    /// The name of the default sink, querying it if it's unknown.
+    pub fn default_sink(&mut self) -> Option<String> {
+        self.cached_default(Device::Sink)
+    }
+
+    /// Changes the volume of the default sink over `duration`, or the default duration.
+    ///
+    /// The transition starts once the current volume is read, when the resulting message is
+    /// passed to [`Self::handle`]. Errors after that are logged.
+    pub fn change_volume(&mut self, change: ChangeVolume, duration: Option<Duration>) {
+        let request = ChangeRequest {
+            duration: duration.map(|d| d.as_secs_f64() * 1e3),
+            ..ChangeRequest::new(change)
+        };
+        self.change(Device::Sink, None, request)
+    }
+    /// Gets the volume of the default sink. This blocks until PulseAudio replies.
+    pub fn get_volume(&mut self) -> Result<VolumeInfo, String> {
+        let name = self.resolve(Device::Sink, None)?;
+        volume_info(
+            Device::Sink,
+            &name,
+            self.backend.get_volume(Device::Sink, &name),
+        )
+    }
+
+    /// Gets the default `device`, querying it if it's unknown.
+    fn cached_default(&mut self, device: Device) -> Option<String> {
+        if let Some(Some(name)) = self.defaults.get(&device) {
+            return Some(name.clone());
+        }
+        log!(self, "QUERY {}", device.to_string().to_uppercase());
+        let name = self.backend.get_default(device);
+        self.defaults.insert(device, name.clone());
+        name
+    }
+    /// Gets the name of the `target` device, or the default device if it's [`None`].
+    ///
+    /// Applications are found by name or index, and ramped by the index of their stream.
+    fn resolve(&mut self, device: Device, target: Option<String>) -> Result<String, String> {
+        if device == Device::SinkInput {
+            let app = target.unwrap_or_default();
+            return self
+                .backend
+                .find_sink_input(&app)
+                .map(|index| index.to_string())
+                .ok_or_else(|| format!("No application `{app}` is playing."));
+        }
+        target
+            .or_else(|| self.cached_default(device))
+            .ok_or_else(|| no_default(device))
+    }
+    /// Starts a transition.
+    ///
+    /// The volume of the device is read without blocking. The transition starts when the
+    /// [`Message::VolumeRead`] with it is handled, and the outcome is replied then.
+    fn change(&mut self, device: Device, target: Option<String>, mut request: ChangeRequest) {
+        log!(self, "Change volume!");
+        let group = target
+            .as_deref()
+            .and_then(|target| target.strip_prefix("group:"));
+        let target = match group {
+            Some(group) if device == Device::Sink => match self.group(group) {
+                Ok(mut members) => {
+                    request.followers = members.split_off(1);
+                    members.pop()
+                }
+                Err(err) => return respond(request.reply.as_ref(), Err(err)),
+            },
+            Some(group) => {
+                let err =
+                    format!("The group `{group}` is of sinks, it can't be used for a {device}.");
+                return respond(request.reply.as_ref(), Err(err));
+            }
+            None => target,
+        };
+        let name = match self.resolve(device, target) {
+            Ok(name) => name,
+            Err(err) => return respond(request.reply.as_ref(), Err(err)),
+        };
+        self.next_id += 1;
+        let key = (device, name);
+        // a change replaces the rest of an envelope
+        self.envelopes.remove(&key);
+        // a newer change of the device supersedes this one if it's read first
+        let read = PendingRead {
+            id: self.next_id,
+            sent: Instant::now(),
+            reply: request.reply.take(),
+        };
+        if let Some(superseded) = self.pending.insert(key.clone(), read) {
+            log!(self, "Dropping a superseded volume change.");
+            respond(superseded.reply.as_ref(), Ok(()));
+        }
+        let pending = PendingChange {
+            id: self.next_id,
+            key,
+            request,
+        };
+        // replies come in order, so the followers are read before the change starts
+        for name in &pending.request.followers {
+            if self.balances.contains_key(&(Device::Sink, name.clone())) {
+                continue;
+            }
+            let messages = self.messages.clone();
+            let read = name.clone();
+            self.backend
+                .query_volume(Device::Sink, name, move |volume| {
+                    if let Some((chs, ..)) = volume {
+                        let _ = messages.send(Message::BalanceRead(read, chs));
+                    }
+                });
+        }
+        let messages = self.messages.clone();
+        let name = pending.key.1.clone();
+        self.backend.query_volume(device, &name, move |volume| {
+            let volume = volume.map(|(v, description, _)| (v, description));
+            let _ = messages.send(Message::VolumeRead(Box::new(pending), volume));
+        });
+    }
+    /// The highest volume changes of the device go to.
+    fn max_volume(&self, key: &(Device, String)) -> f64 {
+        let Settings {
+            min_volume,
+            max_volume,
+            ..
+        } = self.settings;
+        match self.settings.sink_max.get(&key.1) {
+            Some(sink_max) if key.0 == Device::Sink => sink_max.clamp(min_volume, max_volume),
+            _ => max_volume,
+        }
+    }
+    /// Adds a relative `change` to the target of the running transition of the device, if the
+    /// transition started within [`Settings::coalesce_window`].
+    ///
+    /// The transition restarts from its current volume, so a burst of changes makes one
+    /// transition which speeds up, without waiting for the volume to be read.
+    /// Returns `false` if the change has to start its own transition.
+    fn coalesce(
+        &mut self,
+        key: &(Device, String),
+        change: ChangeVolume,
+        saturated: Option<&mpsc::SyncSender<f64>>,
+    ) -> bool {
+        if !matches!(
+            change,
+            ChangeVolume::Increase(_) | ChangeVolume::Multiply(_)
+        ) {
+            return false;
+        }
+        let max_volume = self.max_volume(key);
+        let Settings {
+            min_volume,
+            relative_mode,
+            coalesce_window,
+            ..
+        } = self.settings;
+        let Some(ramp) = self.ramps.get_mut(key) else {
+            return false;
+        };
+        // a change waiting for its read would replace the transition anyway
+        if ramp.mute_after
+            || ramp.start.elapsed() >= coalesce_window
+            || self.pending.contains_key(key)
+        {
+            return false;
+        }
+        let target = change.collapse(ramp.target, relative_mode);
+        let clamped = target.clamp(min_volume, max_volume);
+        if (clamped - target).abs() >= LINEAR_PRECISION / 2. {
+            if let Some(saturated) = saturated {
+                let _ = saturated.try_send(clamped);
+            }
+        }
+        ramp.initial = ramp.current();
+        ramp.target = clamped;
+        ramp.start = Instant::now();
+        ramp.progress = 0.;
+        self.intended.insert(key.clone(), (clamped, Instant::now()));
+        true
+    }
+    /// Gets the sinks of `group` which exist. The missing ones are logged.
+    fn group(&mut self, group: &str) -> Result<Vec<String>, String> {
+        let Some(members) = self.settings.groups.get(group) else {
+            return Err(format!("No group `{group}` is configured."));
+        };
+        let sinks: HashSet<_> = self
+            .backend
+            .list_sinks()
+            .into_iter()
+            .map(|(_, name, _, _)| name)
+            .collect();
+        let (found, missing): (Vec<_>, Vec<_>) = members
+            .iter()
+            .cloned()
+            .partition(|name| sinks.contains(name));
+        if found.is_empty() {
+            return Err(format!("None of the sinks of the group `{group}` exist."));
+        }
+        for name in missing {
+            self.log(
+                format!("The sink `{name}` of the group `{group}` doesn't exist, skipping it."),
+                true,
+            );
+        }
+        Ok(found)
+    }
+    /// Starts the transition of `pending` from the volume `v` of its device.
+    fn start_ramp(&mut self, pending: PendingChange, v: ChannelVolumes) {
+        let Settings {
+            min_volume,
+            easing,
+            gamma,
+            ease_fraction,
+            relative_mode,
+            perceptual,
+            snap_threshold,
+            fade_floor,
+            ..
+        } = self.settings;
+        let PendingChange {
+            key,
+            request:
+                ChangeRequest {
+                    change,
+                    duration: user_duration,
+                    steps,
+                    from,
+                    easing: requested_easing,
+                    gamma: requested_gamma,
+                    fade,
+                    mute_after,
+                    reply,
+                    saturated,
+                    followers,
+                },
+            ..
+        } = pending;
+        let device = key.0;
+        let max_volume = self.max_volume(&key);
+        let chs = match self.balances.get(&key) {
+            Some(chs) if v.max().is_muted() && chs.len() == v.len() => *chs,
+            _ => v,
+        };
+        let mut i_volume = device_volume(&v);
+        let current = match (self.ramps.get(&key), self.intended.get(&key)) {
+            (Some(ramp), _) => ramp.target,
+            (None, Some((intended, at))) if at.elapsed() < INTENDED_WINDOW => {
+                relative_base(i_volume, *intended)
+            }
+            (None, _) => i_volume,
+        };
+        let target_volume = match fade {
+            // fades go all the way to silence
+            Some(true) => {
+                self.pre_fade_volumes.insert(key.clone(), current);
+                0.
+            }
+            Some(false) => {
+                // silence before unmuting to not blast the old level
+                self.set_volume(device, &key.1, &chs, 0.);
+                self.backend.set_mute(device, &key.1, false);
+                i_volume = 0.;
+                self.pre_fade_volumes.remove(&key).unwrap_or(current)
+            }
+            None => {
+                let target = change.collapse(current, relative_mode);
+                let clamped = target.clamp(min_volume, max_volume);
+                if (clamped - target).abs() >= LINEAR_PRECISION / 2. {
+                    if let Some(saturated) = &saturated {
+                        let _ = saturated.try_send(clamped);
+                    }
+                }
+                clamped
+            }
+        };
+        if let Some(from) = from {
+            i_volume = from.clamp(min_volume, max_volume);
+            self.set_volume(device, &key.1, &chs, i_volume);
+        }
+        // each follower keeps its own balance and maximum, the ones without one weren't found
+        let followers: Vec<_> = followers
+            .into_iter()
+            .filter_map(|name| {
+                let key = (Device::Sink, name);
+                let chs = *self.balances.get(&key)?;
+                let max = self.max_volume(&key);
+                Some((key.1, chs, max))
+            })
+            .collect();
+        self.followers.remove(&key);
+        // unmute before raising the volume, so the transition is heard
+        if target_volume >= LINEAR_PRECISION / 2. || fade.is_some() {
+            let names = followers.iter().map(|(name, ..)| name);
+            for name in [&key.1].into_iter().chain(names) {
+                if self.zero_muted.remove(&(device, name.clone())) && fade.is_none() {
+                    self.backend.set_mute(device, name, false);
+                }
+            }
+        }
+        // volumes closer than this read the same, but the followers might not
+        if fade.is_none()
+            && from.is_none()
+            && followers.is_empty()
+            && (target_volume - current).abs() < LINEAR_PRECISION / 2.
+        {
+            log!(self, "Already at {target_volume}, not changing the volume.");
+            respond(reply.as_ref(), Ok(()));
+            // an envelope continues with its next change
+            self.next_segment(key);
+            return;
+        }
+        // nudges feel instant, without the latency of a transition
+        if fade.is_none() && from.is_none() && (target_volume - i_volume).abs() < snap_threshold {
+            log!(self, "Snapping {i_volume} => {target_volume}");
+            self.set_volume(device, &key.1, &chs, target_volume);
+            let group = followers.iter().map(|(name, chs, max)| (name, chs, *max));
+            let devices: Vec<_> = [(&key.1, &chs, max_volume)]
+                .into_iter()
+                .chain(group)
+                .collect();
+            for &(name, chs, max) in &devices[1..] {
+                self.set_volume(device, name, chs, target_volume.min(max));
+            }
+            self.ramps.remove(&key);
+            if self.settings.mute_at_zero && target_volume < LINEAR_PRECISION / 2. {
+                for (name, ..) in devices {
+                    self.backend.set_mute(device, name, true);
+                    self.zero_muted.insert((device, name.clone()));
+                }
+            }
+            self.watchers
+                .retain(|watcher| watcher.send((device, key.1.clone(), target_volume)).is_ok());
+            self.intended
+                .insert(key.clone(), (target_volume, Instant::now()));
+            self.balances.insert(key.clone(), chs);
+            self.snapped.push(Finished {
+                device,
+                name: key.1.clone(),
+                volume: target_volume,
+                muted: false,
+            });
+            respond(reply.as_ref(), Ok(()));
+            self.next_segment(key);
+            return;
+        }
+        let used_duration = self.transition_duration(i_volume, target_volume, user_duration, steps);
+        log!(
+            self,
+            "Initial {i_volume} => {target_volume} in {used_duration:?}"
+        );
+        self.intended
+            .insert(key.clone(), (target_volume, Instant::now()));
+        self.balances.insert(key.clone(), chs);
+        if !followers.is_empty() {
+            self.followers.insert(key.clone(), followers);
+        }
+        self.ramps.insert(
+            key,
+            Ramp {
+                initial: i_volume,
+                target: target_volume,
+                start: Instant::now(),
+                duration: used_duration,
+                progress: 0.,
+                easing: requested_easing.unwrap_or(easing),
+                gamma: requested_gamma.unwrap_or(gamma),
+                ease_fraction,
+                perceptual,
+                floor: fade_floor,
+                channels: chs,
+                mute_after: mute_after || fade == Some(true),
+                failures: 0,
+                written: None,
+            },
+        );
+        respond(reply.as_ref(), Ok(()));
+    }
+    /// How long the transition from `initial` to `target` takes, following the requested
+    /// duration in milliseconds or number of steps, if any.
+    fn transition_duration(
+        &self,
+        initial: f64,
+        target: f64,
+        duration: Option<f64>,
+        steps: Option<u32>,
+    ) -> Duration {
+        let default_duration = if target > initial {
+            self.settings.duration_up
+        } else {
+            self.settings.duration_down
+        };
+        let used_duration = match duration {
+            Some(d) if (0.0..=1e9).contains(&d) => Duration::from_secs_f64(d * 1e-3),
+            _ => default_duration.unwrap_or(self.settings.duration),
+        };
+        // a number of steps takes that many intervals
+        steps.map_or(used_duration, |steps| self.step() * steps)
+    }
+    /// The time between the volume writes of a transition.
+    fn step(&self) -> Duration {
+        if self.settings.interval.is_zero() {
+            ZERO_INTERVAL_STEP
+        } else {
+            self.settings.interval
+        }
+    }
+    /// The volumes a transition from `from` to `to` would write, and when. See
+    /// [`Message::Preview`].
+    fn preview(
+        &self,
+        from: f64,
+        to: f64,
+        duration: Option<f64>,
+        easing: Option<Easing>,
+    ) -> Result<Vec<(Duration, f64)>, String> {
+        let Settings {
+            min_volume,
+            max_volume,
+            ..
+        } = self.settings;
+        let (from, to) = (
+            from.clamp(min_volume, max_volume),
+            to.clamp(min_volume, max_volume),
+        );
+        let duration = self.transition_duration(from, to, duration, None);
+        let step = self.step();
+        let points = duration.as_nanos() / step.as_nanos() + 1;
+        if points > MAX_PREVIEW_POINTS {
+            return Err(format!(
+                "The preview would have {points} points, more than {MAX_PREVIEW_POINTS}."
+            ));
+        }
+        let ramp = Ramp {
+            initial: from,
+            target: to,
+            start: Instant::now(),
+            duration,
+            progress: 0.,
+            easing: easing.unwrap_or(self.settings.easing),
+            gamma: self.settings.gamma,
+            ease_fraction: self.settings.ease_fraction,
+            perceptual: self.settings.perceptual,
+            floor: self.settings.fade_floor,
+            channels: ChannelVolumes::default(),
+            mute_after: false,
+            failures: 0,
+            written: None,
+        };
+        let mut curve: Vec<_> = (0..points as u32)
+            .map(|i| step * i)
+            .take_while(|at| *at < duration)
+            .map(|at| (at, ramp.at(at.as_secs_f64() / duration.as_secs_f64())))
+            .collect();
+        // the target is set when the transition ends
+        curve.push((duration, to));
+        Ok(curve)
+    }
+    /// Replies when the fade up of `to` starts, or returns the error if it doesn't.
+    ///
+    /// The sinks have separate transitions, so one failing doesn't stop the other.
+    fn crossfade(&mut self, from: Option<String>, to: String, reply: Reply) -> Result<(), String> {
+        let from = self.resolve(Device::Sink, from)?;
+        if from == to {
+            return Err(format!("Can't crossfade the sink `{to}` to itself."));
+        }
+        let Some((v, _, muted)) = self.backend.get_volume(Device::Sink, &from) else {
+            return Err(format!(
+                "The volume of the sink `{from}` couldn't be found."
+            ));
+        };
+        // the level the old sink is going to, if it's changing
+        let volume = match self.ramps.get(&(Device::Sink, from.clone())) {
+            Some(ramp) => ramp.target,
+            None if muted => 0.,
+            None => device_volume(&v),
+        };
+        let Some((new, _, new_muted)) = self.backend.get_volume(Device::Sink, &to) else {
+            return Err(format!("The volume of the sink `{to}` couldn't be found."));
+        };
+        // keep the balance through the silence
+        if !new.max().is_muted() {
+            self.balances.insert((Device::Sink, to.clone()), new);
+        }
+        // silence before it becomes the default, to not blast its old level
+        self.set_volume(Device::Sink, &to, &new, 0.);
+        if new_muted {
+            self.backend.set_mute(Device::Sink, &to, false);
+        }
+        if !self.backend.set_default_sink(&to) {
+            // it stays as it was, instead of silent
+            self.set_volume(Device::Sink, &to, &new, device_volume(&new));
+            if new_muted {
+                self.backend.set_mute(Device::Sink, &to, true);
+            }
+            return Err(format!("Failed to set the default sink to `{to}`."));
+        }
+        self.defaults.insert(Device::Sink, Some(to.clone()));
+        let fade_out = ChangeRequest {
+            fade: Some(true),
+            ..ChangeRequest::new(ChangeVolume::Absolute(0.))
+        };
+        self.change(Device::Sink, Some(from), fade_out);
+        let fade_in = ChangeRequest {
+            from: Some(0.),
+            reply: Some(reply),
+            ..ChangeRequest::new(ChangeVolume::Absolute(volume))
+        };
+        self.change(Device::Sink, Some(to), fade_in);
+        Ok(())
+    }
+    /// Starts the first change of `envelope`. The others start from [`Self::tick`] when the
+    /// previous one finishes.
+    fn envelope(&mut self, device: Device, envelope: Envelope, reply: Reply) -> Result<(), String> {
+        let name = self.resolve(device, None)?;
+        let end = match envelope.end {
+            Some(end) => end,
+            None => match self.backend.get_volume(device, &name) {
+                Some((v, _, _)) => device_volume(&v),
+                None => {
+                    return Err(format!(
+                        "The volume of the {device} `{name}` couldn't be found."
+                    ))
+                }
+            },
+        };
+        let mut segments = VecDeque::new();
+        segments.push_back(ChangeRequest {
+            duration: envelope.attack,
+            reply: Some(reply),
+            ..ChangeRequest::new(ChangeVolume::Absolute(envelope.peak))
+        });
+        if envelope.hold > 0. {
+            // starting from the target makes it a transition, not a change which is already done
+            segments.push_back(ChangeRequest {
+                duration: Some(envelope.hold),
+                from: Some(envelope.peak),
+                ..ChangeRequest::new(ChangeVolume::Absolute(envelope.peak))
+            });
+        }
+        segments.push_back(ChangeRequest {
+            duration: envelope.release,
+            ..ChangeRequest::new(ChangeVolume::Absolute(end))
+        });
+        let key = (device, name);
+        self.envelopes.insert(key.clone(), segments);
+        self.next_segment(key);
+        Ok(())
+    }
+    /// Starts the next change of the envelope of `key`. Returns whether there was one.
+    fn next_segment(&mut self, key: (Device, String)) -> bool {
+        let Some(mut segments) = self.envelopes.remove(&key) else {
+            return false;
+        };
+        let Some(segment) = segments.pop_front() else {
+            return false;
+        };
+        let (device, name) = key.clone();
+        self.change(device, Some(name), segment);
+        if !segments.is_empty() {
+            self.envelopes.insert(key, segments);
+        }
+        true
+    }
+    /// Starts the change to the preset `a` or `b`, or returns why it can't.
+    fn toggle_preset(
+        &mut self,
+        device: Device,
+        a: &str,
+        b: &str,
+        reply: Reply,
+    ) -> Result<(), String> {
+        let preset = |name| {
+            self.settings
+                .presets
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("No preset `{name}` is configured."))
+        };
+        let (a, b) = (preset(a)?, preset(b)?);
+        let name = self.resolve(device, None)?;
+        // the target, so toggling again during the transition goes back
+        let current = match self.ramps.get(&(device, name.clone())) {
+            Some(ramp) => ramp.target,
+            None => match self.backend.get_volume(device, &name) {
+                Some((v, _, _)) => device_volume(&v),
+                None => {
+                    return Err(format!(
+                        "The volume of the {device} `{name}` couldn't be found."
+                    ))
+                }
+            },
+        };
+        let target = if (current - a).abs() >= (current - b).abs() {
+            a
+        } else {
+            b
+        };
+        let request = ChangeRequest {
+            reply: Some(reply),
+            ..ChangeRequest::new(ChangeVolume::Absolute(target))
+        };
+        self.change(device, Some(name), request);
+        Ok(())
+    }
+    /// Mutes or unmutes the device `name`, dipping the volume around it with
+    /// [`Settings::confirm_blip`] and `blip`.
+    fn set_mute(&mut self, device: Device, name: String, mute: bool, blip: bool, reply: Reply) {
+        let key = (device, name);
+        // an explicit mute state overrides a pending fade
+        self.zero_muted.remove(&key);
+        if let Some(ramp) = self.ramps.get_mut(&key) {
+            ramp.mute_after = false;
+        }
+        let volume = match self.ramps.get(&key) {
+            Some(ramp) => ramp.target,
+            None => self
+                .backend
+                .get_volume(device, &key.1)
+                .map_or(0., |(v, _, _)| device_volume(&v)),
+        };
+        // a silent device has nothing to dip
+        if !(blip && self.settings.confirm_blip) || volume < LINEAR_PRECISION / 2. {
+            // only the mute flag is touched, so an in-flight transition continues as before
+            self.backend.set_mute(device, &key.1, mute);
+            return respond(Some(&reply), Ok(()));
+        }
+        log!(self, "Blip {volume} before setting mute {mute}");
+        // the dip is heard after unmuting, and before muting
+        if !mute {
+            self.backend.set_mute(device, &key.1, false);
+        }
+        let duration = Some(BLIP_DURATION.as_secs_f64() * 1e3);
+        let dip = volume * BLIP_LEVEL;
+        // starting from the levels makes them transitions, even if they're close
+        let mut segments = VecDeque::new();
+        segments.push_back(ChangeRequest {
+            duration,
+            from: Some(volume),
+            reply: Some(reply),
+            ..ChangeRequest::new(ChangeVolume::Absolute(dip))
+        });
+        segments.push_back(ChangeRequest {
+            duration,
+            from: Some(dip),
+            mute_after: mute,
+            ..ChangeRequest::new(ChangeVolume::Absolute(volume))
+        });
+        self.envelopes.insert(key.clone(), segments);
+        self.next_segment(key);
+    }
+    fn balance(&mut self, device: Device, balance: f64) -> Result<(), String> {
+        if !(-1.0..=1.).contains(&balance) {
+            return Err(format!(
+                "Invalid balance {balance}, expected a value from -1 to 1."
+            ));
+        }
+        let name = self.resolve(device, None)?;
+        let Some((v, _, _)) = self.backend.get_volume(device, &name) else {
+            return Err(format!(
+                "The volume of the {device} `{name}` couldn't be found."
+            ));
+        };
+        if v.len() != 2 {
+            return Err(format!(
+                "The balance can only be set on stereo devices, the {device} `{name}` has {} \
+                channels.",
+                v.len()
+            ));
+        }
+        // keep the balance of muted devices for when they're turned up
+        let loudest = if v.max().is_muted() {
+            Volume::NORMAL
+        } else {
+            v.max()
+        };
+        let mut chs = v;
+        let channels = chs.get_mut();
+        channels[0] = Volume((loudest.0 as f64 * (1. - balance).min(1.)) as u32);
+        channels[1] = Volume((loudest.0 as f64 * (1. + balance).min(1.)) as u32);
+        self.set_volume(device, &name, &chs, device_volume(&v));
+        let key = (device, name);
+        // a running transition continues with the new balance
+        if let Some(ramp) = self.ramps.get_mut(&key) {
+            ramp.channels = chs;
+        }
+        self.balances.insert(key, chs);
+        Ok(())
+    }
+
+    /// Acts on a message, replying through its channel if it has one.
+    pub fn handle(&mut self, message: Message) {
+        let verbose = self.settings.verbose;
+        match message {
+            Message::Change {
+                device,
+                target,
+                volume,
+                duration,
+                steps,
+                from,
+                easing,
+                gamma,
+                reply,
+                saturated,
+            } => {
+                if !self.settings.coalesce_window.is_zero()
+                    && duration.is_none()
+                    && steps.is_none()
+                    && from.is_none()
+                    && easing.is_none()
+                    && gamma.is_none()
+                {
+                    if let Ok(name) = self.resolve(device, target.clone()) {
+                        let key = (device, name);
+                        if self.coalesce(&key, volume, saturated.as_ref()) {
+                            log!(self, "Coalescing volume change of the {device} `{}`", key.1);
+                            // a change replaces the rest of an envelope
+                            self.envelopes.remove(&key);
+                            return respond(reply.as_ref(), Ok(()));
+                        }
+                    }
+                }
+                if self.settings.queue {
+                    if let Ok(name) = self.resolve(device, target.clone()) {
+                        let key = (device, name);
+                        if self.ramps.contains_key(&key) || self.pending.contains_key(&key) {
+                            log!(self, "Queueing volume change of the {device} `{}`", key.1);
+                            let change = Message::Change {
+                                device,
+                                // the device the change was meant for, even if the default changes
+                                target: Some(key.1.clone()),
+                                volume,
+                                duration,
+                                steps,
+                                from,
+                                easing,
+                                gamma,
+                                reply,
+                                saturated,
+                            };
+                            self.queue.push_back((key, change));
+                            return;
+                        }
+                    }
+                }
+                let request = ChangeRequest {
+                    duration,
+                    steps,
+                    from,
+                    easing,
+                    gamma,
+                    reply,
+                    saturated,
+                    ..ChangeRequest::new(volume)
+                };
+                self.change(device, target, request);
+            }
+            Message::VolumeRead(mut pending, volume) => {
+                // superseded or expired, and answered then
+                if self.pending.get(&pending.key).map(|read| read.id) != Some(pending.id) {
+                    return;
+                }
+                pending.request.reply = self.pending.remove(&pending.key).unwrap().reply;
+                match volume {
+                    Some((v, description)) => {
+                        self.descriptions.insert(pending.key.clone(), description);
+                        self.start_ramp(*pending, v)
+                    }
+                    None => {
+                        let (device, name) = &pending.key;
+                        respond(
+                            pending.request.reply.as_ref(),
+                            Err(format!(
+                                "The volume of the {device} `{name}` couldn't be found."
+                            )),
+                        );
+                        self.next_queued(&pending.key);
+                    }
+                }
+            }
+            // fades are regular changes with some extra mute handling
+            Message::Mute(device, MuteAction::Fade(mute), _, reply) => {
+                log!(self, "Fade mute {mute}");
+                let request = ChangeRequest {
+                    fade: Some(mute),
+                    reply: Some(reply),
+                    ..ChangeRequest::new(ChangeVolume::Absolute(0.))
+                };
+                self.change(device, None, request);
+            }
+            Message::GetVolume(device, kind, tx) => {
+                log!(self, "Get volume");
+                match self.resolve(device, None) {
+                    Ok(name) => {
+                        let intended = match kind {
+                            VolumeKind::Intended => {
+                                self.ramps.get(&(device, name.clone())).map(Ramp::current)
+                            }
+                            VolumeKind::Live => None,
+                        };
+                        self.backend.query_volume(device, &name.clone(), move |v| {
+                            let info = volume_info(device, &name, v).map(|mut info| {
+                                if let Some(volume) = intended {
+                                    info.volume = volume;
+                                    info.raw = vol_from_linear(volume).0;
+                                }
+                                info
+                            });
+                            let _ = tx.send(info);
+                        })
+                    }
+                    Err(err) => {
+                        // the client might have timed out
+                        let _ = tx.send(Err(err));
+                    }
+                }
+            }
+            Message::GetChannels(device, tx) => {
+                log!(self, "Get channel volumes");
+                let result = self.resolve(device, None).and_then(|name| {
+                    self.backend.get_channels(device, &name).ok_or_else(|| {
+                        format!("The volume of the {device} `{name}` couldn't be found.")
+                    })
+                });
+                let _ = tx.send(result);
+            }
+            Message::Status(tx) => {
+                let status = if self.ramps.is_empty() {
+                    "idle".to_owned()
+                } else {
+                    let mut lines: Vec<_> = self
+                        .ramps
+                        .iter()
+                        .map(|(key, ramp)| {
+                            let (device, name) = key;
+                            let mut line = format!(
+                                "transitioning target={:.4} current={:.4} {device}={name}",
+                                ramp.target,
+                                ramp.current(),
+                            );
+                            // last, as it can contain spaces
+                            match self.descriptions.get(key) {
+                                Some(description) if !description.is_empty() => {
+                                    line.push_str(" description=");
+                                    line.push_str(description);
+                                }
+                                _ => {}
+                            }
+                            line
+                        })
+                        .collect();
+                    lines.sort_unstable();
+                    lines.join("\n")
+                };
+                let _ = tx.send(status);
+            }
+            Message::ListSinks(tx) => {
+                log!(self, "List sinks");
+                let list = self
+                    .backend
+                    .list_sinks()
+                    .into_iter()
+                    .map(|(index, name, description, volume)| {
+                        format!(
+                            "{index}\t{name}\t{:.2}%\t{description}",
+                            device_volume(&volume) * 100.
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let _ = tx.send(list);
+            }
+            Message::SetDefaultSink(name, tx) => {
+                log!(self, "Set default sink {name}");
+                let success = self.backend.set_default_sink(&name);
+                if success {
+                    self.defaults.insert(Device::Sink, Some(name));
+                }
+                let _ = tx.send(success);
+            }
+            Message::Crossfade(from, to, reply) => {
+                log!(self, "Crossfade {from:?} => {to}");
+                if let Err(err) = self.crossfade(from, to.clone(), reply.clone()) {
+                    respond(Some(&reply), Err(err));
+                }
+            }
+            Message::TogglePreset(device, a, b, reply) => {
+                log!(self, "Toggle presets {a} {b}");
+                if let Err(err) = self.toggle_preset(device, &a, &b, reply.clone()) {
+                    respond(Some(&reply), Err(err));
+                }
+            }
+            Message::Mute(device, MuteAction::Toggle, blip, reply) => {
+                log!(self, "Mute {:?}", MuteAction::Toggle);
+                let result = match self.cached_default(device) {
+                    Some(name) => match self.backend.get_volume(device, &name) {
+                        Some((_, _, muted)) => {
+                            self.set_mute(device, name, !muted, blip, reply.clone());
+                            return;
+                        }
+                        None => Err(format!(
+                            "The volume of the default {device} couldn't be found."
+                        )),
+                    },
+                    None => Err(no_default(device)),
+                };
+                respond(Some(&reply), result);
+            }
+            Message::SetMute(device, mute, blip, reply) => {
+                log!(self, "Set mute {mute}");
+                match self.cached_default(device) {
+                    Some(name) => self.set_mute(device, name, mute, blip, reply),
+                    None => respond(Some(&reply), Err(no_default(device))),
+                }
+            }
+            Message::Balance(device, balance, reply) => {
+                log!(self, "Set balance {balance}");
+                let result = self.balance(device, balance);
+                respond(Some(&reply), result);
+            }
+            Message::DeviceFailed(device, name) => {
+                let key = (device, name);
+                log!(
+                    self,
+                    "Setting the volume of the {device} `{}` failed.",
+                    key.1
+                );
+                if !self.ramps.contains_key(&key) {
+                    return;
+                }
+                let messages = self.messages.clone();
+                let name = key.1.clone();
+                self.backend.query_volume(device, &key.1, move |volume| {
+                    let _ = messages.send(Message::DeviceChecked(device, name, volume.is_some()));
+                });
+            }
+            Message::DeviceChecked(device, name, exists) => {
+                let key = (device, name);
+                // the ramp might have finished since it failed
+                if !self.ramps.contains_key(&key) {
+                    return;
+                }
+                // several writes can fail before the ramp is aborted
+                if exists {
+                    // the device exists, but rejects the volume
+                    let ramp = self.ramps.get_mut(&key).unwrap();
+                    ramp.failures += 1;
+                    let failures = ramp.failures;
+                    if failures >= MAX_WRITE_FAILURES {
+                        self.log(
+                            format!(
+                                "Setting the volume of the {device} `{}` failed {failures} \
+                                times, aborting its volume change.",
+                                key.1
+                            ),
+                            true,
+                        );
+                        self.ramps.remove(&key);
+                        let err = format!("Setting the volume of the {device} `{}` failed.", key.1);
+                        self.clear_queue(Some(&key), Err(err));
+                    }
+                } else {
+                    let ramp = self.ramps.remove(&key).unwrap();
+                    let err = format!("The {device} `{}` disappeared.", key.1);
+                    self.clear_queue(Some(&key), Err(err));
+                    log!(
+                        self,
+                        "The {device} `{}` disappeared, aborting its volume change.",
+                        key.1
+                    );
+                    let was_default = matches!(
+                        self.defaults.remove(&device),
+                        Some(Some(default)) if default == key.1
+                    );
+                    let new_default = self.cached_default(device);
+                    // continue on the new default if the ramp followed the default device
+                    if let (true, Some(new_default)) = (was_default, new_default) {
+                        log!(self, "Continuing the volume change on `{new_default}`.");
+                        // the receiver is ours, but it might be shutting down
+                        let _ = self.messages.send(Message::Change {
+                            device,
+                            target: None,
+                            volume: ChangeVolume::Absolute(ramp.target),
+                            duration: None,
+                            steps: None,
+                            from: None,
+                            easing: None,
+                            gamma: None,
+                            reply: None,
+                            saturated: None,
+                        });
+                    }
+                }
+            }
+            Message::SinkChanged => {
+                log!(self, "Default devices possibly changed.");
+                // queried again when needed before PulseAudio replies
+                self.defaults.clear();
+                let messages = self.messages.clone();
+                self.backend.query_defaults(move |sink, source| {
+                    let _ = messages.send(Message::DefaultsRead(sink, source));
+                });
+            }
+            Message::BalanceRead(name, chs) => {
+                self.balances.insert((Device::Sink, name), chs);
+            }
+            Message::DefaultsRead(sink, source) => {
+                self.defaults.insert(Device::Sink, sink);
+                self.defaults.insert(Device::Source, source);
+            }
+            Message::StateChanged => {
+                log!(self, "State change: {}", self.backend.state());
+                if self.backend.is_lost() {
+                    self.log(
+                        "Lost the connection to PulseAudio. Reconnecting.".to_owned(),
+                        true,
+                    );
+                    self.backend.disconnect();
+                    self.ramps.clear();
+                    self.followers.clear();
+                    let err = "Lost the connection to PulseAudio.".to_owned();
+                    self.clear_queue(None, Err(err.clone()));
+                    self.clear_pending(Err(err));
+                    self.envelopes.clear();
+                    self.defaults.clear();
+                    // in `Self::tick`, so requests are answered (with errors) meanwhile
+                    self.reconnect_at = Some(Instant::now() + RECONNECT_DELAY);
+                }
+            }
+            Message::Cancel => {
+                log!(self, "Cancel transition");
+                self.ramps.clear();
+                self.followers.clear();
+                self.clear_queue(None, Ok(()));
+                self.clear_pending(Ok(()));
+                self.envelopes.clear();
+            }
+            Message::Envelope(device, envelope, reply) => {
+                log!(self, "Envelope {envelope:?}");
+                if let Err(err) = self.envelope(device, envelope, reply.clone()) {
+                    respond(Some(&reply), Err(err));
+                }
+            }
+            Message::Watch(watcher) => {
+                log!(self, "Watch volume");
+                self.watchers.push(watcher.clone());
+                if let Ok(name) = self.resolve(Device::Sink, None) {
+                    self.backend
+                        .query_volume(Device::Sink, &name.clone(), move |v| {
+                            if let Some((v, _, _)) = v {
+                                let _ = watcher.send((Device::Sink, name, device_volume(&v)));
+                            }
+                        });
+                }
+            }
+            Message::VolumeChanged(device) => {
+                let name = (!self.watchers.is_empty())
+                    .then(|| self.cached_default(device))
+                    .flatten();
+                // running transitions are sent by `Self::tick`
+                if let Some(name) =
+                    name.filter(|name| !self.ramps.contains_key(&(device, name.clone())))
+                {
+                    let watchers = self.watchers.clone();
+                    self.backend.query_volume(device, &name.clone(), move |v| {
+                        if let Some((v, _, _)) = v {
+                            let volume = device_volume(&v);
+                            for watcher in watchers {
+                                let _ = watcher.send((device, name.clone(), volume));
+                            }
+                        }
+                    });
+                }
+            }
+            Message::SinkAdded(index) if self.settings.restore_on_resume => {
+                // the last targets are kept even when idle
+                let targets: HashMap<_, _> = self
+                    .intended
+                    .iter()
+                    .filter(|((device, _), _)| *device == Device::Sink)
+                    .map(|((_, name), (target, _))| (name.clone(), *target))
+                    .collect();
+                if !targets.is_empty() {
+                    let messages = self.messages.clone();
+                    self.backend.query_sink_name(index, move |name| {
+                        let Some(name) = name else {
+                            return;
+                        };
+                        if let Some(target) = targets.get(&name) {
+                            if verbose {
+                                eprintln!("The sink `{name}` reappeared, restoring {target}");
+                            }
+                            let _ = messages.send(Message::Change {
+                                device: Device::Sink,
+                                target: Some(name),
+                                volume: ChangeVolume::Absolute(*target),
+                                duration: None,
+                                steps: None,
+                                from: None,
+                                easing: None,
+                                gamma: None,
+                                reply: None,
+                                saturated: None,
+                            });
+                        }
+                    });
+                }
+            }
+            Message::SinkAdded(_) => {}
+            Message::Sleep(after) => {
+                log!(self, "Sleep after {after:?}");
+                self.sleep_deadline = after.map(|after| Instant::now() + after);
+            }
+            Message::Preview {
+                from,
+                to,
+                duration,
+                easing,
+                reply,
+            } => {
+                let _ = reply.send(self.preview(from, to, duration, easing));
+            }
+            Message::Metrics(tx) => {
+                let Metrics {
+                    loops,
+                    loop_total,
+                    loop_max,
+                    ramps,
+                    writes,
+                } = self.metrics;
+                let average = loop_total.as_micros() / u128::from(loops.max(1));
+                let _ = tx.send(format!(
+                    "loops={loops}\nloop-avg-us={average}\nloop-max-us={}\nramps={ramps}\n\
+                    writes={writes}",
+                    loop_max.as_micros()
+                ));
+            }
+            Message::Log(tx) => {
+                let lines: Vec<_> = self.log.iter().map(String::as_str).collect();
+                let _ = tx.send(lines.join("\n"));
+            }
+            Message::Shutdown => self.shutdown(),
+        }
+    }
+
+    /// Sets every device with a running transition to its target, so none is left halfway,
+    /// and disconnects from PulseAudio.
+    pub fn shutdown(&mut self) {
+        log!(self, "Shutting down");
+        for (key, ramp) in mem::take(&mut self.ramps) {
+            let (device, name) = &key;
+            let followers = self.followers.remove(&key).unwrap_or_default();
+            let names = followers.iter().map(|(name, chs, max)| (name, chs, *max));
+            let devices = [(name, &ramp.channels, f64::INFINITY)]
+                .into_iter()
+                .chain(names);
+            for (name, chs, max) in devices {
+                self.set_volume(*device, name, chs, ramp.target.min(max));
+                if ramp.mute_after {
+                    self.backend.set_mute(*device, name, true);
+                }
+            }
+        }
+        let err = "The daemon is shutting down.".to_owned();
+        self.clear_queue(None, Err(err.clone()));
+        self.clear_pending(Err(err));
+        // replies come in order, so the volumes are set when this returns
+        self.backend.sync();
+        self.backend.disconnect();
+        // so the recording is complete
+        self.recorder = None;
+    }
+
+    /// Advances all transitions one step.
+    ///
+    /// Returns the final volume of each device whose transition finished, or which was set at
+    /// once since the last call.
+    pub fn tick(&mut self) -> Vec<Finished> {
+        if self
+            .reconnect_at
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            match self.backend.reconnect() {
+                Ok(()) => {
+                    eprintln!("Reconnected");
+                    self.reconnect_at = None;
+                    let sink = self.backend.get_default(Device::Sink);
+                    self.defaults.insert(Device::Sink, sink);
+                }
+                Err(err) => {
+                    if self.settings.verbose {
+                        eprintln!("{err}");
+                    }
+                    self.reconnect_at = Some(Instant::now() + RECONNECT_DELAY);
+                }
+            }
+        }
+        if self
+            .sleep_deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            self.sleep_deadline = None;
+            log!(self, "Sleep timer reached, fading out");
+            let request = ChangeRequest {
+                duration: Some(SLEEP_FADE_DURATION.as_secs_f64() * 1e3),
+                fade: Some(true),
+                ..ChangeRequest::new(ChangeVolume::Absolute(0.))
+            };
+            self.change(Device::Sink, None, request);
+        }
+        // a lost read would keep the change waiting, and later changes queued behind it
+        let timeout = self.settings.query_timeout;
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, read)| read.sent.elapsed() >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            let read = self.pending.remove(&key).unwrap();
+            let (device, name) = &key;
+            let err = format!("The volume of the {device} `{name}` wasn't read in {timeout:?}.");
+            self.log(err.clone(), false);
+            respond(read.reply.as_ref(), Err(err));
+            self.next_queued(&key);
+        }
+        let mut finished = mem::take(&mut self.snapped);
+        let mut finished_keys = Vec::new();
+        let mut written = Vec::new();
+        let backend = &self.backend;
+        let followers = &self.followers;
+        let intended = &mut self.intended;
+        let zero_muted = &mut self.zero_muted;
+        let mute_at_zero = self.settings.mute_at_zero;
+        let min_write_gap = match self.settings.max_writes_per_second {
+            0 => Duration::ZERO,
+            writes => Duration::from_secs(1) / writes,
+        };
+        let watched = !self.watchers.is_empty();
+        let recorder = &mut self.recorder;
+        let mut writes = 0;
+        self.ramps.retain(|key, ramp| {
+            let (device, name) = key;
+            let (v, done) = ramp.next();
+            // the progress follows the clock, so skipping writes doesn't slow the transition
+            if !done && ramp.written.is_some_and(|at| at.elapsed() < min_write_gap) {
+                return true;
+            }
+            ramp.written = Some(Instant::now());
+            // the sinks of a group follow the first one
+            let group = followers.get(key).map_or(&[][..], Vec::as_slice);
+            let names = group.iter().map(|(name, chs, max)| (name, chs, *max));
+            // the first one is clamped by its own transition
+            let devices = [(name, &ramp.channels, f64::INFINITY)]
+                .into_iter()
+                .chain(names);
+            for (name, chs, max) in devices.clone() {
+                set_volume(backend, recorder, *device, name, chs, v.min(max));
+                writes += 1;
+            }
+            if watched {
+                written.push((*device, name.clone(), v));
+            }
+            if done {
+                // the window of the intended volume starts when it's reached
+                if let Some((_, at)) = intended.get_mut(key) {
+                    *at = Instant::now();
+                }
+                for (name, ..) in devices {
+                    if ramp.mute_after {
+                        backend.set_mute(*device, name, true);
+                    } else if mute_at_zero && v < LINEAR_PRECISION / 2. {
+                        backend.set_mute(*device, name, true);
+                        zero_muted.insert((*device, name.clone()));
+                    }
+                }
+                finished.push(Finished {
+                    device: *device,
+                    name: name.clone(),
+                    volume: if ramp.mute_after { 0. } else { v },
+                    muted: ramp.mute_after,
+                });
+                finished_keys.push(key.clone());
+            }
+            !done
+        });
+        self.metrics.writes += writes;
+        self.metrics.ramps += finished_keys.len() as u64;
+        for volume in written {
+            // disconnected watchers are dropped
+            self.watchers
+                .retain(|watcher| watcher.send(volume.clone()).is_ok());
+        }
+        // start the next queued change from where the finished one ended
+        for key in finished_keys {
+            self.followers.remove(&key);
+            if self.next_segment(key.clone()) {
+                continue;
+            }
+            self.next_queued(&key);
+        }
+        finished
+    }
+    /// Starts the first change queued for the device, see [`Settings::queue`].
+    fn next_queued(&mut self, key: &(Device, String)) {
+        if let Some(i) = self.queue.iter().position(|(queued, _)| queued == key) {
+            let (_, change) = self.queue.remove(i).unwrap();
+            self.handle(change);
+        }
+    }
+    /// Drops the changes queued for the device `key`, or for every device if it's [`None`],
+    /// answering them with `result`.
+    fn clear_queue(&mut self, key: Option<&(Device, String)>, result: Result<(), String>) {
+        let (dropped, kept): (VecDeque<_>, _) = mem::take(&mut self.queue)
+            .into_iter()
+            .partition(|(queued, _)| key.is_none_or(|key| queued == key));
+        self.queue = kept;
+        for (_, change) in dropped {
+            if let Message::Change { reply, .. } = change {
+                respond(reply.as_ref(), result.clone());
+            }
+        }
+    }
+    /// Answers the changes waiting for their volume to be read with `result`, dropping them.
+    fn clear_pending(&mut self, result: Result<(), String>) {
+        for (_, read) in self.pending.drain() {
+            respond(read.reply.as_ref(), result.clone());
+        }
+    }
+}
+
+/// Sets the volume with `backend`, the only place which does, so the `recorder` of
+/// [`VolumeController::record`] gets every write.
+fn set_volume(
+    backend: &impl VolumeBackend,
+    recorder: &mut Option<Watcher>,
+    device: Device,
+    name: &str,
+    channels: &ChannelVolumes,
+    volume: f64,
+) {
+    backend.set_volume(device, name, channels, volume);
+    if let Some(tx) = recorder {
+        // the recording stopped, such as when the file became unwritable
+        if tx.send((device, name.to_owned(), volume)).is_err() {
+            *recorder = None;
+        }
+    }
+}
+
+/// Sends the outcome of a command to the user, or logs it if there is no one to reply to.
+/// The error of a missing default `device`, with its [`pasv_protocol::NO_DEVICE`] code.
+fn no_default(device: Device) -> String {
+    format!(
+        "{}: No default {device} was found.",
+        pasv_protocol::NO_DEVICE
+    )
+}
+fn respond(reply: Option<&Reply>, result: Result<(), String>) {
+    match reply {
+        // the user might have disconnected
+        Some(reply) => {
+            let _ = reply.send(result);
+        }
+        None => {
+            if let Err(err) = result {
+                eprintln!("{err}");
+            }
+        }
+    }
+}
+
+fn volume_info(
+    device: Device,
+    name: &str,
+    volume: Option<(ChannelVolumes, String, bool)>,
+) -> Result<VolumeInfo, String> {
+    volume
+        .map(|(chw, description, muted)| VolumeInfo {
+            volume: device_volume(&chw),
+            raw: chw.max().0,
+            muted,
+            device,
+            name: name.to_owned(),
+            description,
+        })
+        // the device was resolved, so PulseAudio failed to report it
+        .ok_or_else(|| format!("Querying the volume of the {device} `{name}` failed."))
+}
+/// The volume of a device as a single value: the loudest channel.
+///
+/// This is what's reported to the user and what relative changes build on, so both agree on
+/// unbalanced devices. The loudest channel is also the one which reaches the clamp first.
+pub fn device_volume(channels: &ChannelVolumes) -> f64 {
+    vol_to_linear(channels.max())
+}
+/// The step [`vol_to_linear`] rounds to.
+const LINEAR_PRECISION: f64 = 1e-4;
+/// Converts to our linear scale, where 1 is 100%, rounded to [`LINEAR_PRECISION`].
+pub fn vol_to_linear(volume: Volume) -> f64 {
+    (volume.0 as f64 / Volume::NORMAL.0 as f64 * 1e4).round() / 1e4
+}
+/// Converts from our linear scale, where 1 is 100%.
+pub fn vol_from_linear(volume: f64) -> Volume {
+    Volume((volume * Volume::NORMAL.0 as f64).round() as u32)
+}
+/// The base of relative changes: the `intended` volume of the last change if the device
+/// still reads as it, so the rounding of each change doesn't add up.
+fn relative_base(read: f64, intended: f64) -> f64 {
+    if (vol_to_linear(vol_from_linear(intended)) - read).abs() <= LINEAR_PRECISION {
+        intended
+    } else {
+        // changed by someone else
+        read
+    }
+}
+/// Converts from our linear scale to decibels. Zero volume is `-inf`.
+pub fn vol_to_db(volume: f64) -> f64 {
+    if volume <= 0. {
+        return f64::NEG_INFINITY;
+    }
+    VolumeDB::from(vol_from_linear(volume)).0
+}
+pub fn vol_from_db(db: f64) -> f64 {
+    vol_to_linear(Volume::from(VolumeDB(db)))
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn unbalanced_device_volume() {
+        let mut channels = ChannelVolumes::default();
+        channels.set(2, Volume::NORMAL);
+        channels.get_mut()[1] = vol_from_linear(0.5);
+        assert_eq!(device_volume(&channels), 1.);
+
+        // the base of a relative change is the reported volume
+        let reported = device_volume(&channels);
+        let target = ChangeVolume::Increase(-0.2).collapse(reported, RelativeMode::Absolute);
+        assert_eq!(target, 0.8);
+
+        // setting the volume keeps the balance and reads back as the set volume
+        channels.scale(vol_from_linear(target));
+        assert_eq!(device_volume(&channels), 0.8);
+        assert!((vol_to_linear(channels.get()[1]) - 0.4).abs() < 1e-3);
+    }
+
+    #[test]
+    fn every_easing_name_parses() {
+        for name in Easing::NAMES {
+            assert!(Easing::from_name(name).is_some(), "{name}");
+        }
+    }
+
+    #[test]
+    fn ease_ends_is_linear_in_the_middle() {
+        let fraction = 0.2;
+        let at = |progress| Easing::EaseEnds.apply(progress, fraction);
+        assert_eq!(at(0.), 0.);
+        assert_eq!(at(1.), 1.);
+        // the same slope throughout the middle
+        let slope = (at(0.6) - at(0.4)) / 0.2;
+        assert!(((at(0.8) - at(0.2)) / 0.6 - slope).abs() < 1e-9);
+        // and no jump into it
+        assert!((at(fraction + 1e-9) - at(fraction - 1e-9)).abs() < 1e-6);
+        // slow near the ends
+        assert!(at(0.01) < 0.01);
+        assert!(at(0.99) > 0.99);
+        // 0.5 eases all the way, and 0 not at all
+        assert!((Easing::EaseEnds.apply(0.5, 0.5) - 0.5).abs() < 1e-9);
+        assert_eq!(Easing::EaseEnds.apply(0.3, 0.), 0.3);
+    }
+
+    #[test]
+    fn long_ramp_lands_on_target() {
+        // 60 s at the default interval of 10 ms
+        const STEPS: u32 = 6000;
+        let (initial, target) = (0.1, 0.7);
+        let ramp = Ramp {
+            initial,
+            target,
+            start: Instant::now(),
+            duration: Duration::from_secs(60),
+            progress: 0.,
+            easing: Easing::Linear,
+            gamma: 1.,
+            ease_fraction: 0.,
+            perceptual: false,
+            floor: 0.,
+            channels: ChannelVolumes::default(),
+            mute_after: false,
+            failures: 0,
+            written: None,
+        };
+        let mut last = initial;
+        for step in 0..=STEPS {
+            let v = ramp.at(f64::from(step) / f64::from(STEPS));
+            assert!(v >= last && v <= target, "step {step} wrote {v}");
+            last = v;
+        }
+        assert_eq!(ramp.at(0.), initial);
+        assert_eq!(last, target);
+    }
+
+    /// A sink `fake` which keeps the volumes written to it.
+    struct FakeBackend {
+        channels: Rc<RefCell<ChannelVolumes>>,
+        writes: Rc<RefCell<Vec<ChannelVolumes>>>,
+    }
+    impl VolumeBackend for FakeBackend {
+        fn state(&self) -> String {
+            "Ready".to_owned()
+        }
+        fn is_lost(&self) -> bool {
+            false
+        }
+        fn reconnect(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+        fn disconnect(&mut self) {}
+        fn query_defaults(&self, callback: impl FnOnce(Option<String>, Option<String>) + 'static) {
+            callback(Some("fake".to_owned()), None);
+        }
+        fn query_volume(
+            &self,
+            _device: Device,
+            name: &str,
+            callback: impl FnOnce(Option<(ChannelVolumes, String, bool)>) + 'static,
+        ) {
+            let channels = *self.channels.borrow();
+            callback((name == "fake").then(|| (channels, "Fake".to_owned(), false)));
+        }
+        fn query_sink_name(&self, _index: u32, callback: impl FnOnce(Option<String>) + 'static) {
+            callback(None);
+        }
+        fn get_channels(&self, _device: Device, _name: &str) -> Option<Vec<(String, f64)>> {
+            None
+        }
+        fn list_sinks(&self) -> Vec<(u32, String, String, ChannelVolumes)> {
+            Vec::new()
+        }
+        fn find_sink_input(&self, _app: &str) -> Option<u32> {
+            None
+        }
+        fn set_channels(&self, _device: Device, _name: &str, volume: &ChannelVolumes) {
+            *self.channels.borrow_mut() = *volume;
+            self.writes.borrow_mut().push(*volume);
+        }
+        fn set_mute(&self, _device: Device, _name: &str, _mute: bool) {}
+        fn set_default_sink(&mut self, _name: &str) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn surround_channels_keep_their_levels() {
+        // a 5.1 sink with a quieter subwoofer and rear pair
+        let levels = [1., 1., 1., 0.5, 0.8, 0.8];
+        let mut channels = ChannelVolumes::default();
+        channels.set(6, Volume::NORMAL);
+        for (channel, level) in channels.get_mut().iter_mut().zip(levels) {
+            *channel = vol_from_linear(level);
+        }
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let backend = FakeBackend {
+            channels: Rc::new(RefCell::new(channels)),
+            writes: writes.clone(),
+        };
+        let settings = Settings {
+            duration: Duration::from_millis(20),
+            interval: Duration::from_millis(1),
+            ..Settings::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let mut controller = VolumeController::with_backend(backend, settings, tx);
+        controller.handle(Message::Change {
+            device: Device::Sink,
+            target: None,
+            volume: ChangeVolume::Absolute(0.5),
+            duration: None,
+            steps: None,
+            from: None,
+            easing: None,
+            gamma: None,
+            reply: None,
+            saturated: None,
+        });
+        // the read volume comes back as a message
+        while let Ok(message) = rx.try_recv() {
+            controller.handle(message);
+        }
+        while !controller.is_idle() {
+            controller.tick();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let writes = writes.borrow();
+        assert!(writes.len() > 1);
+        for written in writes.iter() {
+            assert_eq!(written.len(), 6);
+            let loudest = vol_to_linear(written.max());
+            for (channel, level) in written.get().iter().zip(levels) {
+                assert!((vol_to_linear(*channel) / loudest - level).abs() < 1e-2);
+            }
+        }
+        assert!((device_volume(writes.last().unwrap()) - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn alternating_nudges_dont_drift() {
+        let start = 0.33337;
+        let mut intended = start;
+        let mut read = vol_to_linear(vol_from_linear(start));
+        for i in 0..100 {
+            let nudge = if i % 2 == 0 { 0.1 } else { -0.1 };
+            intended = ChangeVolume::Increase(nudge)
+                .collapse(relative_base(read, intended), RelativeMode::Absolute);
+            read = vol_to_linear(vol_from_linear(intended));
+        }
+        assert!((intended - start).abs() < 1e-9);
+        assert!((read - start).abs() <= LINEAR_PRECISION);
+
+        // a volume set by someone else is the base instead
+        assert_eq!(relative_base(0.5, intended), 0.5);
+    }
+
+    #[test]
+    fn groups_are_of_sinks() {
+        let backend = FakeBackend {
+            channels: Rc::new(RefCell::new(ChannelVolumes::default())),
+            writes: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut settings = Settings::default();
+        settings
+            .groups
+            .insert("room".to_owned(), vec!["fake".to_owned()]);
+        let (tx, _rx) = mpsc::channel();
+        let mut controller = VolumeController::with_backend(backend, settings, tx);
+        let (reply, replied) = mpsc::sync_channel(1);
+        controller.handle(Message::Change {
+            device: Device::Source,
+            target: Some("group:room".to_owned()),
+            volume: ChangeVolume::Absolute(0.5),
+            duration: None,
+            steps: None,
+            from: None,
+            easing: None,
+            gamma: None,
+            reply: Some(reply),
+            saturated: None,
+        });
+        let err = replied.try_recv().unwrap().unwrap_err();
+        assert!(err.contains("sinks"), "{err}");
+    }
+}
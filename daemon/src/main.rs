@@ -1,42 +1,84 @@
-use std::io::{Read, Write};
-use std::os::unix::net::UnixListener;
+mod config;
+mod dbus;
+mod record;
+mod state;
+
+use std::ffi::CString;
+use std::io::{self, BufRead, Read, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
-use std::{fs, process, thread};
+use std::{env, fs, iter, mem, process, ptr, thread};
 
 use clap::Arg;
-use libpulse_binding::callbacks::ListResult;
-use libpulse_binding::context::{Context, FlagSet, State};
-use libpulse_binding::proplist::Proplist;
-use libpulse_binding::volume::{ChannelVolumes, Volume};
+use config::Config;
+use pa_smooth_volume::{
+    vol_from_db, vol_to_db, ChangeVolume, Device, Easing, Envelope, Message, MuteAction,
+    RelativeMode, Settings, VolumeController, VolumeKind,
+};
+use pasv_protocol::{abstract_name, connect, socket_path};
 
-#[derive(Debug, Clone, Copy)]
-enum ChangeVolume {
-    Increase(f64),
-    Absolute(f64),
-}
-impl ChangeVolume {
-    fn collapse(self, absolute_volume: f64) -> f64 {
-        match self {
-            ChangeVolume::Increase(i) => absolute_volume + i,
-            ChangeVolume::Absolute(a) => a,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-enum Message {
-    Change {
-        volume: ChangeVolume,
-        duration: Option<f64>,
-    },
-    GetVolume(mpsc::SyncSender<Option<f64>>),
-}
+/// The version of the socket protocol, sent in reply to a `version <N>` line.
+///
+/// 1 is the text protocol with volume changes, `get-volume(-json)`, `status`, `list-sinks`,
+/// `set-default-sink`, `(fade-)mute`, `(fade-)unmute`, `mute-toggle`, and `cancel`.
+/// 2 adds `steps=N` in place of the duration.
+/// 3 adds `get-volume-db`.
+/// 4 adds `app:<NAME>` volume changes.
+/// 5 adds `balance <BALANCE>`.
+/// 6 adds `fade <FROM> <TO>`.
+/// 7 adds the easing after the duration.
+/// 8 adds `sleep <SECONDS>` and `cancel-sleep`.
+/// 9 adds ` live` after `get-volume(-json|-db)`.
+/// 10 adds `crossfade [OLD] <NEW>`.
+/// 11 adds the `report:` prefix of volume changes.
+/// 12 adds `watch`.
+/// 13 adds `ping`, replied to with `pong`.
+/// 14 adds `get-volume-raw`.
+/// 15 adds `envelope`.
+/// 16 adds `get-volume-channels`.
+/// 17 adds `log`.
+/// 18 adds ` precision=<DIGITS>` after `get-volume` and `get-volume-channels`, before ` live`.
+/// 19 adds `group:<NAME>` in place of the sink name of volume changes.
+/// 20 adds ` no-blip` after `mute`, `unmute`, and `mute-toggle`.
+/// 21 adds `info`.
+/// 22 adds `toggle-preset <A> <B>`.
+/// 23 adds `metrics`.
+/// 24 executes each line of a connection as a command.
+/// 25 adds `preview <FROM> <TO> [DURATION] [EASING]`, replied to with `<MS>=<VOLUME>` lines.
+/// 26 adds ` gamma=<G>` at the end of volume changes.
+/// 27 adds `clamp`, `min-volume`, and `max-volume` to the reply of `info`.
+/// 28 adds the easing `ease-ends`.
+/// 29 adds the `no-device` code before the error of a missing default device, see
+/// [`pasv_protocol::NO_DEVICE`].
+/// Bump this when adding commands.
+const PROTOCOL_VERSION: u32 = 29;
+/// The most decimals of the percents of `get-volume`.
+const MAX_PRECISION: usize = 6;
+/// How long to wait after `accept` failed for a reason which passes, see [`is_transient`].
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How often to try binding the socket again after it failed, waiting [`REBIND_DELAY`]
+/// (doubling each time) in between.
+const REBIND_ATTEMPTS: u32 = 5;
+const REBIND_DELAY: Duration = Duration::from_millis(500);
+/// How often to check that the socket file is still there, see [`socket_removed`].
+const SOCKET_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Set when shutting down, so the socket removed by it isn't bound again.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
 fn command() -> clap::Command<'static> {
     let cmd: clap::Command<'static> = clap::command!();
-    cmd.arg(
+    cmd.after_help(
+        "Options can also be set in `$XDG_CONFIG_HOME/pasvd/config.toml` \
+        (defaulting to `~/.config/pasvd/config.toml`), \
+        using the long names as keys, e.g. `duration = 200` or `no-clamp = true`.",
+    )
+    .arg(
         Arg::new("duration")
             .long("duration")
             .short('d')
@@ -44,12 +86,28 @@ fn command() -> clap::Command<'static> {
             .help("Duration of volume change in milliseconds.")
             .value_parser(clap::value_parser!(u64)),
     )
+    .arg(
+        Arg::new("duration-up")
+            .long("duration-up")
+            .help("Duration of volume increases in milliseconds. Defaults to --duration.")
+            .value_parser(clap::value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("duration-down")
+            .long("duration-down")
+            .help("Duration of volume decreases in milliseconds. Defaults to --duration.")
+            .value_parser(clap::value_parser!(u64)),
+    )
     .arg(
         Arg::new("interval")
             .long("interval")
             .short('i')
             .default_value("10")
-            .help("Interval between volume changes in milliseconds.")
+            .help(
+                "Interval between volume changes in milliseconds. 0 writes the volume as fast \
+                as possible during a volume change, without sleeping, and `steps=N` changes \
+                take N milliseconds.",
+            )
             .value_parser(clap::value_parser!(u64)),
     )
     .arg(
@@ -58,308 +116,1683 @@ fn command() -> clap::Command<'static> {
             .short('p')
             .help(
                 "Path to bind the command receiving UNIX socket to. \
-                Defaults to `/run/user/<uid>/pasvd`.",
+                A path starting with `@`, such as `@pasvd`, is an abstract socket address \
+                without a file. Defaults to `$XDG_RUNTIME_DIR/pasvd`, or \
+                `/run/user/<uid>/pasvd` if it isn't set.",
+            )
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("socket-mode")
+            .long("socket-mode")
+            .help(
+                "The permissions of the socket, in octal, such as `0600` to only \
+                let the user send commands. Defaults to the umask.",
+            )
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("socket-group")
+            .long("socket-group")
+            .help("Set the group of the socket, so the group's members can send commands.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("server")
+            .long("server")
+            .help(
+                "The PulseAudio server to connect to, such as `tcp:host` or \
+                `unix:/path/to/socket`. Defaults to `PULSE_SERVER` or the local server.",
             )
             .takes_value(true),
     )
     .arg(
         Arg::new("no-clamp")
             .long("no-clamp")
-            .help("Don't clamp volume range to 100%."),
+            .help("Don't clamp volume range to 100%, or to --max-volume."),
+    )
+    .arg(
+        Arg::new("max-volume")
+            .long("max-volume")
+            .default_value("1")
+            .help("The highest volume changes go to, e.g. `1.5` for 150%.")
+            .value_parser(clap::value_parser!(f64)),
+    )
+    .arg(
+        Arg::new("sink-max")
+            .long("sink-max")
+            .help(
+                "Lower the highest volume of a sink, as `NAME=VOLUME`, such as \
+                `alsa_output.pci-0000_00_1f.3.analog-stereo=0.6`. Can be given several times. \
+                In the config, separate them with commas.",
+            )
+            .takes_value(true)
+            .multiple_occurrences(true),
+    )
+    .arg(
+        Arg::new("group")
+            .long("group")
+            .help(
+                "Name a group of sinks which change together, as `NAME=SINK+SINK...`. \
+                Changes of the sink `group:NAME` go to all of them. Can be given several times. \
+                In the config, separate them with commas.",
+            )
+            .takes_value(true)
+            .multiple_occurrences(true),
+    )
+    .arg(
+        Arg::new("preset")
+            .long("preset")
+            .help(
+                "Name a volume, as `NAME=VOLUME`, such as `quiet=0.2`. The command \
+                `toggle-preset A B` changes to whichever of two presets is further from the \
+                volume. Can be given several times. In the config, separate them with commas.",
+            )
+            .takes_value(true)
+            .multiple_occurrences(true),
+    )
+    .arg(
+        Arg::new("min-volume")
+            .long("min-volume")
+            .default_value("0")
+            .help("The lowest volume changes go to. Fades to mute still go to zero.")
+            .value_parser(clap::value_parser!(f64)),
     )
     .arg(
         Arg::new("verbose")
             .long("verbose")
             .help("Print more information of the Pulseaudio operations."),
     )
+    .arg(
+        Arg::new("connect-retries")
+            .long("connect-retries")
+            .default_value("0")
+            .help(
+                "Try connecting to PulseAudio this many more times on startup, waiting longer \
+                between each, such as when pasvd starts before PulseAudio.",
+            )
+            .value_parser(clap::value_parser!(u32)),
+    )
+    .arg(
+        Arg::new("query-timeout")
+            .long("query-timeout")
+            .default_value("2000")
+            .help(
+                "How many milliseconds to wait for PulseAudio to answer a query before \
+                treating the device as missing, instead of hanging.",
+            )
+            .value_parser(clap::value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("log-lines")
+            .long("log-lines")
+            .default_value("0")
+            .help(
+                "Keep this many of the latest --verbose log lines, even without --verbose, \
+                for `pasv --log`. 0 keeps none.",
+            )
+            .value_parser(clap::value_parser!(usize)),
+    )
+    .arg(
+        Arg::new("easing")
+            .long("easing")
+            .short('e')
+            .default_value("linear")
+            .help("The curve the volume follows during a volume change.")
+            .value_parser(Easing::NAMES),
+    )
+    .arg(
+        Arg::new("gamma")
+            .long("gamma")
+            .default_value("1")
+            .help(
+                "Raise the progress of volume changes to this power before the easing. \
+                Above 1 starts slower, below 1 starts faster. Volume changes can override it \
+                with `gamma=<G>` last.",
+            )
+            .value_parser(clap::value_parser!(f64)),
+    )
+    .arg(
+        Arg::new("ease-fraction")
+            .long("ease-fraction")
+            .default_value("0.1")
+            .help(
+                "How much of the progress at each end of volume changes the easing `ease-ends` \
+                eases, from 0 to 0.5. The middle is linear.",
+            )
+            .value_parser(clap::value_parser!(f64)),
+    )
+    .arg(
+        Arg::new("relative-mode")
+            .long("relative-mode")
+            .default_value("absolute")
+            .help(
+                "Whether relative changes such as `+10%` add to the volume (`absolute`) \
+                or scale it (`multiplicative`, 10% louder than the current volume).",
+            )
+            .value_parser(RelativeMode::NAMES),
+    )
+    .arg(
+        Arg::new("read-timeout")
+            .long("read-timeout")
+            .default_value("5000")
+            .help(
+                "How long to wait for a client to send its command, in milliseconds. \
+                0 waits forever.",
+            )
+            .value_parser(clap::value_parser!(u64)),
+    )
+    .arg(Arg::new("perceptual").long("perceptual").help(
+        "Interpolate volume changes in decibels, \
+        which sounds more even than linear changes.",
+    ))
+    .arg(
+        Arg::new("snap-threshold")
+            .long("snap-threshold")
+            .default_value("0.005")
+            .help(
+                "Volume changes smaller than this, such as `0.01` for 1%, \
+                are set at once instead of transitioning.",
+            )
+            .value_parser(clap::value_parser!(f64)),
+    )
+    .arg(
+        Arg::new("fade-floor")
+            .long("fade-floor")
+            .default_value("0")
+            .help(
+                "Volume changes skip the volumes below this, such as `0.02` for 2%, \
+                which are practically inaudible. Lowering the volume to below it stops at \
+                the floor and then sets the target, which --mute-at-zero mutes if it's 0.",
+            )
+            .value_parser(clap::value_parser!(f64)),
+    )
+    .arg(
+        Arg::new("coalesce-window")
+            .long("coalesce-window")
+            .default_value("0")
+            .help(
+                "Relative changes arriving within this many milliseconds of the last change \
+                of a device retarget its running transition instead of restarting it, \
+                such as when scrolling. 0 disables it.",
+            )
+            .value_parser(clap::value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("max-writes-per-second")
+            .long("max-writes-per-second")
+            .default_value("0")
+            .help(
+                "Write the volume of a device at most this many times per second during a \
+                volume change, skipping the steps in between, so other PulseAudio clients \
+                aren't flooded with events. The target is always written. 0 is unlimited.",
+            )
+            .value_parser(clap::value_parser!(u32)),
+    )
+    .arg(
+        Arg::new("restore-on-resume")
+            .long("restore-on-resume")
+            .help(
+                "When a sink reappears, such as after a suspend, \
+        change its volume back to the target of its last volume change.",
+            ),
+    )
+    .arg(
+        Arg::new("fade-in-on-start")
+            .long("fade-in-on-start")
+            .help(
+                "On startup, silence the default sink and fade it up to its volume over this \
+                many milliseconds. With --restore, it fades up to the remembered volume \
+                instead.",
+            )
+            .value_parser(clap::value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("start-volume")
+            .long("start-volume")
+            .help("The volume --fade-in-on-start fades up to, instead of the current volume.")
+            .value_parser(clap::value_parser!(f64)),
+    )
+    .arg(
+        Arg::new("signal-step")
+            .long("signal-step")
+            .help(
+                "Change the volume of the default sink up by this step on SIGUSR1 and down \
+                on SIGUSR2, such as `5%` or `3dB`, for media keys without starting pasv. \
+                The socket keeps working alongside.",
+            )
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("signal-duration")
+            .long("signal-duration")
+            .help("The duration of the changes of --signal-step. Defaults to --duration.")
+            .value_parser(clap::value_parser!(u64)),
+    )
+    .arg(Arg::new("stdin").long("stdin").help(
+        "Also read commands from stdin, one per line, in the protocol of the socket. \
+        The replies are printed to stdout, and logs to stderr.",
+    ))
+    .arg(
+        Arg::new("exit-on-eof")
+            .long("exit-on-eof")
+            .help("With --stdin, shut down when stdin is closed instead of continuing."),
+    )
+    .arg(Arg::new("restore").long("restore").help(
+        "Remember the volume of the default sink each time a volume change of it finishes, \
+        by its name in `$XDG_STATE_HOME/pasvd/last`, and change the default sink to its \
+        remembered volume on startup. Fades to mute aren't remembered. A missing or corrupt \
+        file skips the restore.",
+    ))
+    .arg(
+        Arg::new("record")
+            .long("record")
+            .help(
+                "Append each volume written by volume changes to this file, as the \
+                milliseconds since the start, the device, its name, and the volume. For \
+                checking the shape of volume changes, such as against `preview`.",
+            )
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("decimal-comma")
+            .long("decimal-comma")
+            .help("Accept a comma as the decimal separator of volumes, such as `0,5`."),
+    )
+    .arg(Arg::new("auto-interval").long("auto-interval").help(
+        "Measure how long PulseAudio takes to set the volume on startup, and raise \
+        --interval so the volume isn't written faster than it responds.",
+    ))
     .arg(Arg::new("print-timings").long("print-timings").help(
         "Print timing information. Useful \
         for performance debugging regarding slow Pulseaudio callbacks.",
     ))
+    .arg(Arg::new("print-config").long("print-config").help(
+        "Print the options in effect, from the command line, the config, and the defaults, \
+        as `key=value` lines on startup.",
+    ))
+    .arg(
+        Arg::new("dry-run")
+            .long("dry-run")
+            .help("Exit after reading the options, without connecting to PulseAudio."),
+    )
+    .arg(
+        Arg::new("force")
+            .long("force")
+            .help("Take over the socket even if another pasvd answers on it."),
+    )
+    .arg(Arg::new("mute-at-zero").long("mute-at-zero").help(
+        "Mute devices when a volume change ends at 0%, and unmute them when the next \
+        change raises the volume. Doesn't unmute devices muted with fade-mute.",
+    ))
+    .arg(Arg::new("confirm-blip").long("confirm-blip").help(
+        "Briefly dip the volume before muting and after unmuting, as an audible cue. \
+        fade-mute and fade-unmute don't dip, and `no-blip` after a mute command skips it.",
+    ))
+    .arg(Arg::new("queue").long("queue").help(
+        "Start volume changes after the running change of the device finishes, \
+        instead of replacing it.",
+    ))
+    .arg(
+        Arg::new("notify")
+            .long("notify")
+            .help("Show a desktop notification with the volume when a volume change finishes."),
+    )
+    .arg(Arg::new("dbus").long("dbus").help(
+        "Also accept commands over D-Bus, on the session bus as `dev.icelk.pasvd`. \
+        The socket keeps working.",
+    ))
+}
+
+fn config_error(err: String) -> ! {
+    eprintln!("{err}");
+    process::exit(1);
+}
+
+/// What the `info` command reports about the running daemon.
+struct DaemonInfo {
+    start: Instant,
+    /// The path of the command socket.
+    path: std::path::PathBuf,
+    /// The bounds of volume changes. The maximum is infinite with `--no-clamp`.
+    min_volume: f64,
+    max_volume: f64,
 }
 
 fn main() {
+    let start = Instant::now();
     let cmd = command();
     let matches = cmd.get_matches();
-    let duration = Duration::from_millis(*matches.get_one("duration").unwrap());
-    let interval = Duration::from_millis(*matches.get_one("interval").unwrap());
-    let path = matches
-        .get_one::<&str>("path")
-        .map_or_else(socket_path, std::path::PathBuf::from);
-    let clamp = !matches.contains_id("no-clamp");
-    let verbose = matches.contains_id("verbose");
-    let print_timings = matches.contains_id("print-timings");
+    let config = Config::load().unwrap_or_else(|err| config_error(err));
+    let value = |id| {
+        config
+            .value(&matches, id)
+            .unwrap_or_else(|err| config_error(err))
+    };
+    let flag = |id| {
+        config
+            .flag(&matches, id)
+            .unwrap_or_else(|err| config_error(err))
+    };
 
-    let mut ml = libpulse_binding::mainloop::threaded::Mainloop::new()
-        .expect("failed to create a libpulse Mainloop");
-
-    let mut props = Proplist::new().unwrap();
-    props
-        .set_str(
-            libpulse_binding::proplist::properties::APPLICATION_NAME,
-            "pa-smooth-volume",
-        )
-        .unwrap();
-
-    let mut ctx = Context::new_with_proplist(&ml, "pa-smooth-volume", &props)
-        .expect("failed to create a libpulse Context");
-    let (tx, rx) = mpsc::channel();
-
-    ctx.set_state_callback(Some(Box::new(move || {
-        tx.send(()).unwrap();
-    })));
-
-    ctx.connect(None, FlagSet::NOFLAGS, None)
-        .expect("failed to connect to PA");
-
-    ml.start().unwrap();
-
-    loop {
-        // wait for connection
-        rx.recv().unwrap();
-        println!("State change: {:?}", ctx.get_state());
-        if let State::Ready = ctx.get_state() {
-            break;
+    let duration = Duration::from_millis(value("duration"));
+    let directional_duration = |id| {
+        config
+            .optional(&matches, id)
+            .unwrap_or_else(|err| config_error(err))
+            .map(Duration::from_millis)
+    };
+    let duration_up = directional_duration("duration-up");
+    let duration_down = directional_duration("duration-down");
+    let mut interval = Duration::from_millis(value("interval"));
+    let decimal_comma = flag("decimal-comma");
+    let read_timeout = Some(Duration::from_millis(value("read-timeout"))).filter(|t| !t.is_zero());
+    let path = config
+        .optional::<String>(&matches, "path")
+        .unwrap_or_else(|err| config_error(err))
+        .map_or_else(socket_path, std::path::PathBuf::from);
+    let socket_mode = config
+        .optional::<String>(&matches, "socket-mode")
+        .unwrap_or_else(|err| config_error(err))
+        .map(|mode| {
+            u32::from_str_radix(&mode, 8)
+                .ok()
+                .filter(|mode| *mode <= 0o7777)
+                .unwrap_or_else(|| {
+                    config_error(format!(
+                        "Invalid socket mode `{mode}`, expected octal permissions such as `0600`."
+                    ))
+                })
+        });
+    let socket_group = config
+        .optional::<String>(&matches, "socket-group")
+        .unwrap_or_else(|err| config_error(err))
+        .map(|group| group_id(&group).unwrap_or_else(|err| config_error(err)));
+    if abstract_name(&path).is_some() && (socket_mode.is_some() || socket_group.is_some()) {
+        config_error(
+            "Abstract sockets have no permissions, --socket-mode and --socket-group \
+            need a path."
+                .to_owned(),
+        );
+    }
+    let volume_bound = |id| {
+        config
+            .value::<f64>(&matches, id)
+            .unwrap_or_else(|err| config_error(err))
+    };
+    let min_volume = volume_bound("min-volume");
+    let max_volume = if flag("no-clamp") {
+        f64::INFINITY
+    } else {
+        volume_bound("max-volume")
+    };
+    if !(0. ..=max_volume).contains(&min_volume) {
+        config_error(format!(
+            "The minimum volume {min_volume} has to be between 0 and the maximum volume."
+        ));
+    }
+    let sink_max = config
+        .values(&matches, "sink-max")
+        .into_iter()
+        .map(|mapping| {
+            mapping
+                .rsplit_once('=')
+                .and_then(|(name, max)| Some((name.trim().to_owned(), max.trim().parse().ok()?)))
+                // `clamp` needs the maximum to be at least the minimum
+                .filter(|(_, max): &(String, f64)| *max >= min_volume)
+                .unwrap_or_else(|| {
+                    config_error(format!(
+                        "Invalid sink maximum `{mapping}`, expected `NAME=VOLUME` \
+                        with a volume of at least the minimum volume."
+                    ))
+                })
+        })
+        .collect();
+    let groups = config
+        .values(&matches, "group")
+        .into_iter()
+        .map(|mapping| {
+            mapping
+                .split_once('=')
+                .map(|(name, sinks)| {
+                    let sinks: Vec<_> = sinks
+                        .split('+')
+                        .map(str::trim)
+                        .filter(|sink| !sink.is_empty())
+                        .map(str::to_owned)
+                        .collect();
+                    (name.trim().to_owned(), sinks)
+                })
+                .filter(|(name, sinks)| !name.is_empty() && !sinks.is_empty())
+                .unwrap_or_else(|| {
+                    config_error(format!(
+                        "Invalid group `{mapping}`, expected `NAME=SINK+SINK...`."
+                    ))
+                })
+        })
+        .collect();
+    let presets = config
+        .values(&matches, "preset")
+        .into_iter()
+        .map(|mapping| {
+            mapping
+                .rsplit_once('=')
+                .and_then(|(name, volume)| {
+                    Some((name.trim().to_owned(), volume.trim().parse().ok()?))
+                })
+                .filter(|(name, volume): &(String, f64)| !name.is_empty() && *volume >= 0.)
+                .unwrap_or_else(|| {
+                    config_error(format!(
+                        "Invalid preset `{mapping}`, expected `NAME=VOLUME`."
+                    ))
+                })
+        })
+        .collect();
+    let verbose = flag("verbose");
+    let print_timings = flag("print-timings");
+    let perceptual = flag("perceptual");
+    let dbus = flag("dbus");
+    let notifier = flag("notify").then(|| dbus::notifier(verbose));
+    let restore = flag("restore");
+    // checked here instead of by clap, which doesn't see the config file
+    if flag("exit-on-eof") && !flag("stdin") {
+        config_error("--exit-on-eof requires --stdin.".to_owned());
+    }
+    let record: Option<String> = config
+        .optional(&matches, "record")
+        .unwrap_or_else(|err| config_error(err));
+    let signal_step: Option<String> = config
+        .optional(&matches, "signal-step")
+        .unwrap_or_else(|err| config_error(err));
+    let nudge = signal_step.as_deref().map(|step| {
+        // unsigned steps are increases
+        let signed = if step.starts_with(['+', '-']) {
+            step.to_owned()
+        } else {
+            format!("+{step}")
+        };
+        let (up, down) = match parse_volume(&signed) {
+            Some(ChangeVolume::Increase(i)) => {
+                (ChangeVolume::Increase(i), ChangeVolume::Increase(-i))
+            }
+            Some(ChangeVolume::Multiply(m)) => {
+                (ChangeVolume::Multiply(m), ChangeVolume::Multiply(1. / m))
+            }
+            _ => config_error(format!(
+                "Invalid signal step `{step}`, expected a relative volume such as `5%`."
+            )),
+        };
+        SignalNudge {
+            up,
+            down,
+            duration: directional_duration("signal-duration")
+                .map(|duration| duration.as_secs_f64() * 1e3),
         }
+    });
+    if signal_step.is_none() && directional_duration("signal-duration").is_some() {
+        config_error("--signal-duration requires --signal-step.".to_owned());
+    }
+    let fade_in_on_start = directional_duration("fade-in-on-start");
+    let start_volume: Option<f64> = config
+        .optional(&matches, "start-volume")
+        .unwrap_or_else(|err| config_error(err));
+    if start_volume.is_some() && fade_in_on_start.is_none() {
+        config_error("--start-volume requires --fade-in-on-start.".to_owned());
     }
-    println!("Connected");
+    let easing_name: String = config
+        .value(&matches, "easing")
+        .unwrap_or_else(|err| config_error(err));
+    let easing = Easing::from_name(&easing_name).unwrap_or_else(|| {
+        config_error(format!(
+            "Invalid easing `{easing_name}`, expected one of {}.",
+            Easing::NAMES.join(", ")
+        ))
+    });
+    let gamma = config
+        .value(&matches, "gamma")
+        .ok()
+        .and_then(valid_gamma)
+        .unwrap_or_else(|| config_error("--gamma has to be a positive number.".to_owned()));
+    let ease_fraction = config
+        .value(&matches, "ease-fraction")
+        .ok()
+        .filter(|fraction| (0. ..=0.5).contains(fraction))
+        .unwrap_or_else(|| config_error("--ease-fraction has to be from 0 to 0.5.".to_owned()));
+    let relative_mode_name: String = config
+        .value(&matches, "relative-mode")
+        .unwrap_or_else(|err| config_error(err));
+    let relative_mode = RelativeMode::from_name(&relative_mode_name).unwrap_or_else(|| {
+        config_error(format!(
+            "Invalid relative mode `{relative_mode_name}`, expected one of {}.",
+            RelativeMode::NAMES.join(", ")
+        ))
+    });
 
-    let mut volume = None;
-    let mut initial_volume = None;
-    let mut step = None;
-    let mut iterations = 0_u32;
+    let settings = Settings {
+        duration,
+        duration_up,
+        duration_down,
+        interval,
+        min_volume,
+        max_volume,
+        sink_max,
+        groups,
+        presets,
+        easing,
+        gamma,
+        ease_fraction,
+        relative_mode,
+        perceptual,
+        snap_threshold: volume_bound("snap-threshold"),
+        fade_floor: volume_bound("fade-floor"),
+        coalesce_window: Duration::from_millis(value("coalesce-window")),
+        max_writes_per_second: config
+            .value(&matches, "max-writes-per-second")
+            .unwrap_or_else(|err| config_error(err)),
+        mute_at_zero: flag("mute-at-zero"),
+        confirm_blip: flag("confirm-blip"),
+        restore_on_resume: flag("restore-on-resume"),
+        queue: flag("queue"),
+        verbose,
+        log_lines: config
+            .value(&matches, "log-lines")
+            .unwrap_or_else(|err| config_error(err)),
+        connect_retries: config
+            .value(&matches, "connect-retries")
+            .unwrap_or_else(|err| config_error(err)),
+        query_timeout: Duration::from_millis(value("query-timeout")),
+        server: config
+            .optional(&matches, "server")
+            .unwrap_or_else(|err| config_error(err)),
+    };
 
-    let mut sink = get_default_sink(&ctx);
-    println!("Got sink.");
-    let mut channels = sink.as_ref().and_then(|sink| get_channels(sink, &ctx));
-    let mut sink_last_changed = Instant::now();
+    if flag("print-config") {
+        let none = || "none".to_owned();
+        let millis = |d: Option<Duration>| d.map_or_else(none, |d| d.as_millis().to_string());
+        let config_path = Config::path().map_or_else(none, |p| p.display().to_string());
+        println!("config={config_path}");
+        println!("duration={}", duration.as_millis());
+        println!("duration-up={}", millis(settings.duration_up));
+        println!("duration-down={}", millis(settings.duration_down));
+        println!("interval={}", interval.as_millis());
+        println!("read-timeout={}", read_timeout.map_or(0, |t| t.as_millis()));
+        println!("path={}", path.display());
+        println!(
+            "socket-mode={}",
+            socket_mode.map_or_else(none, |m| format!("{m:04o}"))
+        );
+        println!(
+            "socket-group={}",
+            socket_group.map_or_else(none, |g| g.to_string())
+        );
+        println!("server={}", settings.server.clone().unwrap_or_else(none));
+        println!("clamp={}", max_volume.is_finite());
+        println!("min-volume={min_volume}");
+        println!("max-volume={max_volume}");
+        let mut sink_max: Vec<_> = settings.sink_max.iter().collect();
+        sink_max.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        for (name, max) in sink_max {
+            println!("sink-max={name}={max}");
+        }
+        let mut groups: Vec<_> = settings.groups.iter().collect();
+        groups.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        for (name, sinks) in groups {
+            println!("group={name}={}", sinks.join("+"));
+        }
+        let mut presets: Vec<_> = settings.presets.iter().collect();
+        presets.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        for (name, volume) in presets {
+            println!("preset={name}={volume}");
+        }
+        println!("easing={easing_name}");
+        println!("gamma={}", settings.gamma);
+        println!("ease-fraction={}", settings.ease_fraction);
+        println!("relative-mode={relative_mode_name}");
+        println!("perceptual={perceptual}");
+        println!("snap-threshold={}", settings.snap_threshold);
+        println!("fade-floor={}", settings.fade_floor);
+        println!("coalesce-window={}", settings.coalesce_window.as_millis());
+        println!("max-writes-per-second={}", settings.max_writes_per_second);
+        println!("mute-at-zero={}", settings.mute_at_zero);
+        println!("confirm-blip={}", settings.confirm_blip);
+        println!("restore-on-resume={}", settings.restore_on_resume);
+        println!("restore={restore}");
+        println!("record={}", record.clone().unwrap_or_else(none));
+        println!("signal-step={}", signal_step.unwrap_or_else(none));
+        println!(
+            "signal-duration={}",
+            nudge
+                .and_then(|nudge| nudge.duration)
+                .map_or_else(none, |ms| ms.to_string())
+        );
+        println!("fade-in-on-start={}", millis(fade_in_on_start));
+        println!(
+            "start-volume={}",
+            start_volume.map_or_else(none, |volume| volume.to_string())
+        );
+        println!("queue={}", settings.queue);
+        println!("verbose={verbose}");
+        println!("log-lines={}", settings.log_lines);
+        println!("connect-retries={}", settings.connect_retries);
+        println!("query-timeout={}", settings.query_timeout.as_millis());
+        println!("print-timings={print_timings}");
+        println!("decimal-comma={decimal_comma}");
+        println!("stdin={}", flag("stdin"));
+        println!("exit-on-eof={}", flag("exit-on-eof"));
+        println!("auto-interval={}", flag("auto-interval"));
+        println!("notify={}", notifier.is_some());
+        println!("dbus={dbus}");
+    }
+    if flag("dry-run") {
+        process::exit(0);
+    }
+    if !flag("force") && is_running(&path) {
+        config_error(format!(
+            "Another pasvd is already listening on {}. Stop it, or use --force to take over \
+            the socket.",
+            path.display()
+        ));
+    }
 
     let (change_volume, rx_change_volume) = mpsc::channel();
 
+    let mut controller = VolumeController::new(settings, change_volume.clone())
+        .unwrap_or_else(|err| config_error(err));
+    if flag("auto-interval") {
+        interval = controller.tune_interval();
+    }
+    let recording = record.map(|record| {
+        let (recorder, thread) = record::writer(record.into(), start);
+        controller.record(recorder);
+        thread
+    });
+    let (remember, remembering) = restore.then(|| state::writer(verbose)).unzip();
+    let restored = restore
+        .then(|| controller.default_sink())
+        .flatten()
+        .and_then(|sink| state::load(&sink));
+    if let Some(fade_in) = fade_in_on_start {
+        // one change to the restored volume, instead of two fighting
+        let volume = match start_volume.or(restored) {
+            Some(volume) => ChangeVolume::Absolute(volume),
+            None => ChangeVolume::Multiply(1.),
+        };
+        if verbose {
+            eprintln!("Fading in over {fade_in:?}.");
+        }
+        // without a default sink, there's nothing to fade in
+        let (reply, _) = mpsc::sync_channel(1);
+        controller.handle(Message::Change {
+            device: Device::Sink,
+            target: None,
+            volume,
+            duration: Some(fade_in.as_secs_f64() * 1e3),
+            steps: None,
+            from: Some(0.),
+            easing: None,
+            gamma: None,
+            reply: Some(reply),
+            saturated: None,
+        });
+    } else if let Some(volume) = restored {
+        if verbose {
+            eprintln!("Restoring the volume {volume}.");
+        }
+        controller.handle(Message::Change {
+            device: Device::Sink,
+            target: None,
+            volume: ChangeVolume::Absolute(volume),
+            duration: None,
+            steps: None,
+            from: None,
+            easing: None,
+            gamma: None,
+            reply: None,
+            saturated: None,
+        });
+    }
+
+    if dbus {
+        let change_volume = change_volume.clone();
+        thread::spawn(move || {
+            let err = dbus::serve(&change_volume);
+            eprintln!("D-Bus interface stopped: {err}");
+        });
+    }
+    forward_signals(change_volume.clone(), nudge);
+    let info = Arc::new(DaemonInfo {
+        start,
+        path: path.clone(),
+        min_volume,
+        max_volume,
+    });
+    if flag("stdin") {
+        let change_volume = change_volume.clone();
+        let info = info.clone();
+        let exit_on_eof = flag("exit-on-eof");
+        thread::spawn(move || read_stdin(&change_volume, decimal_comma, &info, exit_on_eof));
+    }
     {
+        let change_volume = change_volume.clone();
+        let path = path.clone();
         thread::spawn(move || {
-            let _ = fs::remove_file(&path);
-            let listener =
-                UnixListener::bind(&path).expect("failed to listen for commands from the user");
-            while let Ok((mut stream, _)) = listener.accept() {
-                let mut buf = String::new();
-                if let Err(err) = stream.read_to_string(&mut buf) {
-                    eprintln!("Failed to read target volume from socket: {err}");
-                    continue;
-                };
-                let mut trimmed = buf.trim();
-                if trimmed == "get-volume" {
-                    let (tx, rx) = mpsc::sync_channel(1);
-                    change_volume.send(Message::GetVolume(tx)).unwrap();
-                    let v = rx.recv().unwrap();
-                    if let Some(v) = v {
-                        let s = format!("{:.2}%", v * 100.);
-                        let _ = stream.write_all(s.as_bytes());
+            let mut listener =
+                listen(&path, socket_mode, socket_group).unwrap_or_else(|err| config_error(err));
+            loop {
+                // accepting doesn't fail when the socket file is removed, it's just not reached
+                match wait_for_connection(&listener, SOCKET_CHECK_INTERVAL) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        if socket_removed(&path) && !SHUTTING_DOWN.load(Ordering::Relaxed) {
+                            eprintln!(
+                                "The socket {} was removed, binding it again.",
+                                path.display()
+                            );
+                            listener = rebind(&path, socket_mode, socket_group);
+                        }
+                        continue;
+                    }
+                    Err(err) => {
+                        eprintln!("Failed to wait for connections: {err}");
+                        thread::sleep(ACCEPT_RETRY_DELAY);
                     }
-                    continue;
-                }
-                let duration: Option<f64> = if let Some((v, duration)) = trimmed.split_once(' ') {
-                    trimmed = v.trim();
-                    duration.parse().ok()
-                } else {
-                    None
-                };
-                let relative = trimmed.starts_with('+') || trimmed.starts_with('-');
-                let num = if relative { &trimmed[1..] } else { trimmed };
-                let percent = num.ends_with('%');
-                let num = num.strip_suffix('%').unwrap_or(num);
-                let mut target: f64 = if let Ok(v) = num.parse() {
-                    v
-                } else {
-                    eprintln!("Failed to parse volume command from socket.");
-                    continue;
-                };
-                if percent {
-                    target /= 100.;
                 }
-
-                let v = if relative {
-                    if trimmed.starts_with('+') {
-                        ChangeVolume::Increase(target)
-                    } else {
-                        ChangeVolume::Increase(-target)
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    // the client gave up since the wait
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(err) if is_transient(&err) => {
+                        eprintln!("Failed to accept a connection: {err}");
+                        // running out of file descriptors fails at once until some are closed
+                        thread::sleep(ACCEPT_RETRY_DELAY);
+                        continue;
+                    }
+                    Err(err) => {
+                        eprintln!("The socket failed: {err}");
+                        listener = rebind(&path, socket_mode, socket_group);
+                        continue;
                     }
-                } else {
-                    ChangeVolume::Absolute(target)
                 };
-
-                change_volume
-                    .send(Message::Change {
-                        volume: v,
-                        duration,
-                    })
-                    .unwrap();
+                // the listener doesn't block, so it can be checked
+                let blocking = stream.set_nonblocking(false);
+                if let Err(err) = blocking.and_then(|()| stream.set_read_timeout(read_timeout)) {
+                    eprintln!("Failed to set the read timeout of the socket: {err}");
+                }
+                let change_volume = change_volume.clone();
+                let info = info.clone();
+                // so a client which doesn't finish writing doesn't block the others
+                thread::spawn(move || {
+                    handle_connection(stream, &change_volume, decimal_comma, &info)
+                });
             }
-            process::exit(0);
         });
     }
 
     loop {
-        let message = if volume.is_none() {
+        let message = if controller.is_idle() {
             if verbose {
-                println!("Waiting for command.");
+                eprintln!("Waiting for command.");
+            }
+            match controller.deadline() {
+                Some(deadline) => rx_change_volume
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .ok(),
+                None => Some(rx_change_volume.recv().unwrap()),
             }
-            Some(rx_change_volume.recv().unwrap())
         } else {
             rx_change_volume.try_recv().ok()
         };
         let start = Instant::now();
-        match message {
-            Some(Message::Change {
-                volume: change,
-                duration: user_duration,
-            }) => {
-                if verbose {
-                    println!("Change volume!");
+        // PulseAudio reports each written step, so one message per tick would fall behind
+        let pending = iter::from_fn(|| rx_change_volume.try_recv().ok());
+        for message in message.into_iter().chain(pending) {
+            let shutdown = matches!(message, Message::Shutdown);
+            controller.handle(message);
+            if shutdown {
+                SHUTTING_DOWN.store(true, Ordering::Relaxed);
+                if abstract_name(&path).is_none() {
+                    let _ = fs::remove_file(&path);
                 }
-                if sink.is_none() || sink_last_changed.elapsed() > Duration::from_secs(1) {
-                    if verbose {
-                        println!("QUERY SINK");
-                    }
-                    sink = get_default_sink(&ctx);
-                    sink_last_changed = Instant::now();
+                // the shutdown dropped the recorder, so this flushes the file
+                if let Some(recording) = recording {
+                    let _ = recording.join();
                 }
-                if let Some(sink) = &sink {
-                    if let Some((v, _sink_idx, chs)) = get_volume(sink, &ctx) {
-                        let i_volume = vol_to_linear(v.max());
-                        let mut target_volume = change
-                            .collapse(if let Some(v) = volume { v } else { i_volume })
-                            .max(0.);
-                        if clamp {
-                            target_volume = target_volume.min(1.);
-                        }
-                        volume = Some(target_volume);
-                        initial_volume = Some(i_volume);
-                        let used_duration = match user_duration {
-                            Some(d) if (0.0..=1e9).contains(&d) => {
-                                Duration::from_secs_f64(d * 1e-3)
-                            }
-                            _ => duration,
-                        };
-                        if used_duration <= interval {
-                            step = Some(target_volume - i_volume)
-                        } else {
-                            step = Some(
-                                (target_volume - i_volume)
-                                    / (used_duration.as_millis() / interval.as_millis()) as f64,
-                            );
-                        }
-                        iterations = 0;
-                        channels = Some(chs);
-                        if verbose {
-                            println!(
-                                "Initial {i_volume} => {target_volume} by steps {}",
-                                step.unwrap()
-                            );
-                        }
-                    } else {
-                        eprintln!("The volume of the default sink couldn't be found.");
-                        continue;
-                    }
-                } else {
-                    eprintln!("No default sink was found.");
-                    continue;
+                // so the last remembered volumes are written
+                drop(remember);
+                if let Some(remembering) = remembering {
+                    let _ = remembering.join();
                 }
+                process::exit(0);
             }
-            Some(Message::GetVolume(tx)) => {
-                if verbose {
-                    println!("Get volume");
-                }
-                if let Some(sink) = &sink {
-                    let v = get_volume(sink, &ctx);
-                    tx.send(v.map(|(chw, _, _)| vol_to_linear(chw.avg())))
-                        .unwrap();
-                } else {
-                    tx.send(None).unwrap();
+        }
+        for finished in controller.tick() {
+            // the level muted from is kept instead of silence, and only the default sink's
+            if let Some(remember) = &remember {
+                if finished.device == Device::Sink
+                    && !finished.muted
+                    && controller.default_sink().as_ref() == Some(&finished.name)
+                {
+                    let _ = remember.send((finished.name.clone(), finished.volume));
                 }
             }
-            None => {}
-        }
-        if let (Some(target), Some(initial_volume), Some(step), Some(sink), Some(channels)) =
-            (volume, initial_volume, step, &sink, channels)
-        {
-            let mut v = initial_volume + step * iterations as f64;
-            if step.is_sign_positive() {
-                if v >= target {
-                    volume = None;
-                    v = target;
-                }
-            } else if v <= target {
-                volume = None;
-                v = target;
+            if let Some(notifier) = &notifier {
+                let _ = notifier.send((finished.device, finished.volume));
             }
-
-            set_volume(sink, channels, v, &ctx);
-
-            iterations += 1;
-        } else {
-            volume = None;
         }
         let loop_duration = start.elapsed();
+        controller.record_loop(loop_duration);
         if print_timings {
-            println!("Loop took {loop_duration:?}");
+            eprintln!("Loop took {loop_duration:?}");
         }
         thread::sleep(interval.saturating_sub(loop_duration));
     }
 }
 
-fn get_default_sink(ctx: &Context) -> Option<String> {
-    let (tx, rx) = mpsc::channel();
-    ctx.introspect().get_server_info(move |info| {
-        tx.send(
-            info.default_sink_name
-                .as_ref()
-                .map(|c| c.clone().into_owned()),
+/// The pipe written to by [`on_signal`].
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // only async-signal-safe functions can be called here
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Relaxed),
+            (&byte as *const u8).cast(),
+            1,
         )
-        .unwrap();
+    };
+}
+/// The volume changes on SIGUSR1 and SIGUSR2, with the duration in milliseconds.
+#[derive(Debug, Clone, Copy)]
+struct SignalNudge {
+    up: ChangeVolume,
+    down: ChangeVolume,
+    duration: Option<f64>,
+}
+/// Sends [`Message::Shutdown`] to `messages` on SIGTERM or SIGINT, and the changes of `nudge`
+/// on SIGUSR1 and SIGUSR2.
+fn forward_signals(messages: mpsc::Sender<Message>, nudge: Option<SignalNudge>) {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        eprintln!("Failed to handle signals: {}", io::Error::last_os_error());
+        return;
+    }
+    SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+    let mut read = unsafe { fs::File::from_raw_fd(fds[0]) };
+    thread::spawn(move || {
+        let mut byte = [0];
+        while read.read_exact(&mut byte).is_ok() {
+            let signal = libc::c_int::from(byte[0]);
+            let (Some(nudge), libc::SIGUSR1 | libc::SIGUSR2) = (nudge, signal) else {
+                eprintln!("Received a shutdown signal.");
+                let _ = messages.send(Message::Shutdown);
+                return;
+            };
+            let _ = messages.send(Message::Change {
+                device: Device::Sink,
+                target: None,
+                volume: if signal == libc::SIGUSR1 {
+                    nudge.up
+                } else {
+                    nudge.down
+                },
+                duration: nudge.duration,
+                steps: None,
+                from: None,
+                easing: None,
+                gamma: None,
+                reply: None,
+                saturated: None,
+            });
+        }
     });
-    rx.recv().unwrap()
+    let nudges: &[_] = if nudge.is_some() {
+        &[libc::SIGUSR1, libc::SIGUSR2]
+    } else {
+        &[]
+    };
+    for &signal in [libc::SIGTERM, libc::SIGINT].iter().chain(nudges) {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as usize;
+            // so blocking calls, such as accepting connections, aren't interrupted
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+    }
+}
+
+/// Reads the commands from `stream`, one per line, and writes their replies.
+///
+/// With `decimal_comma`, volumes can use a comma as the decimal separator, such as `0,5`.
+fn handle_connection(
+    mut stream: UnixStream,
+    messages: &mpsc::Sender<Message>,
+    decimal_comma: bool,
+    info: &DaemonInfo,
+) {
+    let mut buf = String::new();
+    if let Err(err) = stream.read_to_string(&mut buf) {
+        eprintln!("Failed to read target volume from socket: {err}");
+        return;
+    };
+    let mut command = buf.as_str();
+    let mut reply = String::new();
+    // an optional handshake line, answered without involving the main loop
+    if let Some((version, rest)) = command
+        .trim_start()
+        .strip_prefix("version ")
+        .map(|rest| rest.split_once('\n').unwrap_or((rest, "")))
+    {
+        if version.trim().parse::<u32>().is_ok() {
+            reply = format!("version {PROTOCOL_VERSION}");
+            command = rest;
+        }
+    }
+    if command.trim() == "watch" {
+        return watch(stream, &reply, messages);
+    }
+    // a command per line, executed in order, with the replies on as many lines
+    let mut commands: Vec<_> = command
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    if commands.is_empty() && reply.is_empty() {
+        // the empty command is replied to with its error
+        commands.push(command);
+    }
+    for command in commands {
+        if !reply.is_empty() {
+            reply.push('\n');
+        }
+        reply.push_str(&execute(command, messages, decimal_comma, info));
+    }
+    let _ = stream.write_all(reply.as_bytes());
 }
-fn get_volume(sink: &str, ctx: &Context) -> Option<(ChannelVolumes, u32, u8)> {
+/// Executes a command of the line protocol. Returns the reply, or `ERR: <error>`.
+fn execute(
+    command: &str,
+    messages: &mpsc::Sender<Message>,
+    decimal_comma: bool,
+    info: &DaemonInfo,
+) -> String {
+    match handle_command(&normalize_volumes(command, decimal_comma), messages, info) {
+        Ok(reply) => reply,
+        Err(err) => {
+            eprintln!("{err}");
+            format!("ERR: {err}")
+        }
+    }
+}
+/// Executes a command per line of stdin, printing the replies to stdout. At the end of
+/// stdin, the daemon shuts down if `exit_on_eof`, and otherwise keeps serving the socket.
+fn read_stdin(
+    messages: &mpsc::Sender<Message>,
+    decimal_comma: bool,
+    info: &DaemonInfo,
+    exit_on_eof: bool,
+) {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        println!("{}", execute(&line, messages, decimal_comma, info));
+    }
+    if exit_on_eof {
+        let _ = messages.send(Message::Shutdown);
+    }
+}
+/// Writes a line with the volume and device each time a volume changes, until the client
+/// disconnects. `handshake` is written first, if it isn't empty.
+fn watch(mut stream: UnixStream, handshake: &str, messages: &mpsc::Sender<Message>) {
+    if !handshake.is_empty() && writeln!(stream, "{handshake}").is_err() {
+        return;
+    }
     let (tx, rx) = mpsc::channel();
-    ctx.introspect().get_sink_info_by_name(sink, move |info| {
-        if let ListResult::Item(info) = info {
-            tx.send(Some((info.volume, info.index, info.volume.len())))
-                .unwrap();
+    messages.send(Message::Watch(tx)).unwrap();
+    // dropping `rx` when the client is gone unregisters it
+    for (device, name, volume) in rx {
+        if writeln!(stream, "{:.2}% {device}={name}", volume * 100.).is_err() {
+            break;
+        }
+    }
+}
+/// Sends a message with a reply channel to the main loop and waits for the reply.
+fn request<T>(
+    messages: &mpsc::Sender<Message>,
+    message: impl FnOnce(mpsc::SyncSender<T>) -> Message,
+) -> Result<T, String> {
+    let (tx, rx) = mpsc::sync_channel(1);
+    messages.send(message(tx)).unwrap();
+    // such as when the request was cancelled or PulseAudio went away
+    rx.recv()
+        .map_err(|_| "The daemon dropped the request.".to_owned())
+}
+
+/// Executes a command received from the user. Returns the reply to send back.
+fn handle_command(
+    command: &str,
+    messages: &mpsc::Sender<Message>,
+    info: &DaemonInfo,
+) -> Result<String, String> {
+    let (device, mut trimmed) = match command.trim().strip_prefix("source:") {
+        Some(rest) => (Device::Source, rest.trim()),
+        None => (Device::Sink, command.trim()),
+    };
+    let ok = |result: Result<(), String>| result.map(|()| "OK".to_owned());
+    // the intended volume follows the transition, `live` queries the device
+    let (query, kind) = match trimmed.strip_suffix(" live") {
+        Some(query) => (query, VolumeKind::Live),
+        None => (trimmed, VolumeKind::Intended),
+    };
+    // the decimals of the percents, before `live`
+    let (query, precision) = match query.rsplit_once(" precision=") {
+        Some((query @ ("get-volume" | "get-volume-channels"), digits)) => {
+            let precision = digits
+                .parse()
+                .ok()
+                .filter(|digits| *digits <= MAX_PRECISION)
+                .ok_or_else(|| {
+                    format!("Invalid precision `{digits}`, expected 0 to {MAX_PRECISION} digits.")
+                })?;
+            (query, precision)
+        }
+        _ => (query, 2),
+    };
+    // mute changes can skip the dip of --confirm-blip
+    let (query, blip) = match query.strip_suffix(" no-blip") {
+        Some(query @ ("mute" | "unmute" | "mute-toggle")) => (query, false),
+        _ => (query, true),
+    };
+    match query {
+        "get-volume" | "get-volume-json" | "get-volume-db" | "get-volume-raw" => {
+            let info = request(messages, |tx| Message::GetVolume(device, kind, tx))??;
+            return Ok(if query == "get-volume-json" {
+                info.to_json()
+            } else if query == "get-volume-raw" {
+                format!("{}\nmuted={}", info.raw, info.muted)
+            } else if query == "get-volume-db" {
+                let db = vol_to_db(info.volume);
+                if db == f64::NEG_INFINITY {
+                    format!("-inf dB\nmuted={}", info.muted)
+                } else {
+                    format!("{db:.1} dB\nmuted={}", info.muted)
+                }
+            } else {
+                // mute on its own line, so consumers parsing the leading number still work
+                let mut reply =
+                    format!("{:.precision$}%\nmuted={}", info.volume * 100., info.muted);
+                if !info.description.is_empty() {
+                    reply.push_str("\ndescription=");
+                    reply.push_str(&info.description);
+                }
+                reply
+            });
+        }
+        "get-volume-channels" => {
+            let channels = request(messages, |tx| Message::GetChannels(device, tx))??;
+            let channels: Vec<_> = channels
+                .iter()
+                .map(|(label, volume)| format!("{label}={:.precision$}%", volume * 100.))
+                .collect();
+            return Ok(channels.join(" "));
+        }
+        "status" => return request(messages, Message::Status),
+        "list-sinks" => return request(messages, Message::ListSinks),
+        "mute-toggle" => {
+            return ok(request(messages, |tx| {
+                Message::Mute(device, MuteAction::Toggle, blip, tx)
+            })?)
+        }
+        "fade-mute" | "fade-unmute" => {
+            let action = MuteAction::Fade(query == "fade-mute");
+            return ok(request(messages, |tx| {
+                Message::Mute(device, action, blip, tx)
+            })?);
+        }
+        "cancel" => {
+            messages.send(Message::Cancel).unwrap();
+            return ok(Ok(()));
+        }
+        "ping" => return Ok("pong".to_owned()),
+        "info" => {
+            // a missing default sink is left empty, the other lines are still useful
+            let sink = request(messages, |tx| {
+                Message::GetVolume(Device::Sink, VolumeKind::Live, tx)
+            })
+            .and_then(|volume| volume)
+            .map_or_else(|_| String::new(), |volume| volume.name);
+            return Ok(format!(
+                "version={}\nprotocol={PROTOCOL_VERSION}\nuptime={}\nsocket={}\ndefault-sink={sink}\n\
+                clamp={}\nmin-volume={}\nmax-volume={}",
+                env!("CARGO_PKG_VERSION"),
+                info.start.elapsed().as_secs(),
+                info.path.display(),
+                info.max_volume.is_finite(),
+                info.min_volume,
+                info.max_volume,
+            ));
+        }
+        "log" => return request(messages, Message::Log),
+        "metrics" => return request(messages, Message::Metrics),
+        "cancel-sleep" => {
+            messages.send(Message::Sleep(None)).unwrap();
+            return ok(Ok(()));
+        }
+        "mute" | "unmute" => {
+            let mute = query == "mute";
+            return ok(request(messages, |tx| {
+                Message::SetMute(device, mute, blip, tx)
+            })?);
+        }
+        _ => {}
+    }
+    if let Some(seconds) = trimmed.strip_prefix("sleep ") {
+        let after = seconds
+            .trim()
+            .parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .ok_or_else(|| format!("Failed to parse sleep duration `{}`.", seconds.trim()))?;
+        messages.send(Message::Sleep(Some(after))).unwrap();
+        return ok(Ok(()));
+    }
+    if let Some(balance) = trimmed.strip_prefix("balance ") {
+        let balance = balance
+            .trim()
+            .parse()
+            .map_err(|_| format!("Failed to parse balance `{}`.", balance.trim()))?;
+        return ok(request(messages, |tx| {
+            Message::Balance(device, balance, tx)
+        })?);
+    }
+    if let Some(name) = trimmed.strip_prefix("set-default-sink ") {
+        let name = name.trim().to_owned();
+        return if request(messages, |tx| Message::SetDefaultSink(name, tx))? {
+            Ok("Default sink set.".to_owned())
         } else {
-            tx.send(None).unwrap();
+            Err("Failed to set the default sink.".to_owned())
+        };
+    }
+    // `envelope peak=<VOLUME> [attack=<MS>] [hold=<MS>] [release=<MS>] [end=<VOLUME>]`
+    if let Some(options) = trimmed.strip_prefix("envelope ") {
+        let mut envelope = Envelope {
+            attack: None,
+            peak: f64::NAN,
+            hold: 0.,
+            release: None,
+            end: None,
+        };
+        for option in options.split_whitespace() {
+            let invalid = || format!("Invalid envelope option `{option}`.");
+            let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+            let value: f64 = value
+                .parse()
+                .ok()
+                .filter(|value: &f64| *value >= 0.)
+                .ok_or_else(invalid)?;
+            match key {
+                "attack" => envelope.attack = Some(value),
+                "peak" => envelope.peak = value,
+                "hold" => envelope.hold = value,
+                "release" => envelope.release = Some(value),
+                "end" => envelope.end = Some(value),
+                _ => return Err(invalid()),
+            }
         }
-    });
-    let mut first = None;
-    while let Some(item) = rx.recv().unwrap() {
-        first = Some(item);
+        if envelope.peak.is_nan() {
+            return Err("The envelope needs a `peak=<VOLUME>`.".to_owned());
+        }
+        return ok(request(messages, |tx| {
+            Message::Envelope(device, envelope, tx)
+        })?);
+    }
+    // `preview <FROM> <TO> [DURATION] [EASING]`
+    if let Some(options) = trimmed.strip_prefix("preview ") {
+        let mut options = options.split_whitespace();
+        let mut volume = || match options.next().map(|volume| (volume, parse_volume(volume))) {
+            Some((_, Some(ChangeVolume::Absolute(volume)))) => Ok(volume),
+            Some((volume, _)) => Err(format!(
+                "Invalid preview volume `{volume}`, it has to be absolute."
+            )),
+            None => Err("A preview needs the volumes to change from and to.".to_owned()),
+        };
+        let (from, to) = (volume()?, volume()?);
+        let mut duration = None;
+        let mut easing = None;
+        for option in options {
+            match Easing::from_name(option) {
+                Some(name) if easing.is_none() => easing = Some(name),
+                None if duration.is_none() && easing.is_none() => {
+                    duration = parse_duration(option)?
+                }
+                _ => return Err(format!("Unexpected `{option}` in the preview.")),
+            }
+        }
+        let curve = request(messages, |reply| Message::Preview {
+            from,
+            to,
+            duration,
+            easing,
+            reply,
+        })??;
+        let lines: Vec<_> = curve
+            .iter()
+            .map(|(at, volume)| format!("{}={volume:.4}", (at.as_secs_f64() * 1e3).round()))
+            .collect();
+        return Ok(lines.join("\n"));
+    }
+    if let Some(presets) = trimmed.strip_prefix("toggle-preset ") {
+        let mut presets = presets.split_whitespace().map(str::to_owned);
+        let (Some(a), Some(b), None) = (presets.next(), presets.next(), presets.next()) else {
+            return Err(format!(
+                "Failed to parse toggle-preset `{}`, expected two presets.",
+                command.trim()
+            ));
+        };
+        return ok(request(messages, |tx| {
+            Message::TogglePreset(device, a, b, tx)
+        })?);
+    }
+    // `crossfade [OLD] NEW`, where OLD defaults to the default sink
+    if let Some(sinks) = trimmed.strip_prefix("crossfade ") {
+        let mut sinks = sinks.split_whitespace().map(str::to_owned);
+        let (from, to) = match (sinks.next(), sinks.next(), sinks.next()) {
+            (Some(to), None, None) => (None, to),
+            (Some(from), Some(to), None) => (Some(from), to),
+            _ => return Err(format!("Failed to parse crossfade `{}`.", command.trim())),
+        };
+        return ok(request(messages, |tx| Message::Crossfade(from, to, tx))?);
     }
-    first
+    let parse_error = || format!("Failed to parse volume command `{}`.", command.trim());
+    // `report:` replies `SATURATED <VOLUME>` instead of `OK` if the target was clamped
+    let report = if let Some(rest) = trimmed.strip_prefix("report:") {
+        trimmed = rest.trim();
+        true
+    } else {
+        false
+    };
+    // `fade <FROM> <TO>` starts from FROM instead of the current volume
+    let from = if let Some(rest) = trimmed.strip_prefix("fade ") {
+        let (from, rest) = rest.trim().split_once(' ').ok_or_else(parse_error)?;
+        trimmed = rest.trim();
+        match parse_volume(from) {
+            Some(ChangeVolume::Absolute(from)) => Some(from),
+            _ => {
+                return Err(format!(
+                    "Invalid start volume `{from}` for fade, it has to be absolute."
+                ))
+            }
+        }
+    } else {
+        None
+    };
+    // `app:<NAME>` targets the stream of an application, by its name or index
+    let (device, name) = if let Some(rest) = trimmed.strip_prefix("app:") {
+        let (app, rest) = rest.split_once(' ').ok_or_else(parse_error)?;
+        trimmed = rest.trim();
+        (Device::SinkInput, Some(app.to_owned()))
+    } else {
+        // an optional device name precedes the volume
+        let name = match trimmed.split_once(' ') {
+            Some((name, rest))
+                if !name.starts_with(|c: char| {
+                    c.is_ascii_digit() || c == '+' || c == '-' || c == '.'
+                }) =>
+            {
+                trimmed = rest.trim();
+                Some(name.to_owned())
+            }
+            _ => None,
+        };
+        (device, name)
+    };
+    // an optional `gamma=<G>` last
+    let gamma =
+        match trimmed.rsplit_once(" gamma=") {
+            Some((rest, gamma)) => {
+                trimmed = rest.trim();
+                let valid = gamma.parse().ok().and_then(valid_gamma);
+                Some(valid.ok_or_else(|| {
+                    format!("Invalid gamma `{gamma}`, expected a positive number.")
+                })?)
+            }
+            None => None,
+        };
+    // either a duration or `steps=N`, then the easing, which can also be given alone
+    let mut steps = None;
+    let mut easing = None;
+    let duration: Option<f64> = if let Some((v, rest)) = trimmed.split_once(' ') {
+        trimmed = v.trim();
+        let mut tokens = rest.split_whitespace();
+        let mut duration = tokens.next().unwrap_or_default();
+        let easing_name = match tokens.next() {
+            Some(name) => Some(name),
+            None if Easing::from_name(duration).is_some() => Some(mem::take(&mut duration)),
+            None => None,
+        };
+        if let Some(extra) = tokens.next() {
+            return Err(format!("Unexpected `{extra}` after the easing."));
+        }
+        if let Some(name) = easing_name {
+            easing = Some(Easing::from_name(name).ok_or_else(|| {
+                format!(
+                    "Unknown easing `{name}`, expected one of {}.",
+                    Easing::NAMES.join(", ")
+                )
+            })?);
+        }
+        if let Some(n) = duration.strip_prefix("steps=") {
+            match n.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(format!(
+                        "Invalid number of steps `{n}`, expected a whole number of at least 1."
+                    ))
+                }
+                Ok(n) => steps = Some(n),
+            }
+            None
+        } else {
+            parse_duration(duration)?
+        }
+    } else {
+        None
+    };
+    let volume = parse_volume(trimmed).ok_or_else(parse_error)?;
+    if from.is_some() && !matches!(volume, ChangeVolume::Absolute(_)) {
+        return Err(format!(
+            "Invalid target volume `{trimmed}` for fade, it has to be absolute."
+        ));
+    }
+
+    let (saturated, rx_saturated) = mpsc::sync_channel(1);
+    request(messages, |tx| Message::Change {
+        device,
+        target: name,
+        volume,
+        duration,
+        steps,
+        from,
+        easing,
+        gamma,
+        reply: Some(tx),
+        saturated: report.then_some(saturated),
+    })??;
+    // sent before the reply, if at all
+    Ok(match rx_saturated.try_recv() {
+        Ok(volume) => format!("SATURATED {volume:.2}"),
+        Err(_) => "OK".to_owned(),
+    })
 }
-fn get_channels(sink: &str, ctx: &Context) -> Option<u8> {
-    get_volume(sink, ctx).map(|(_, _, chs)| chs)
+
+/// [`None`] unless `gamma` is a positive, finite power.
+fn valid_gamma(gamma: f64) -> Option<f64> {
+    (gamma.is_finite() && gamma > 0.).then_some(gamma)
 }
-fn set_volume(sink: &str, channels: u8, vol: f64, ctx: &Context) {
-    let mut volume = ChannelVolumes::default();
-    volume.set_len(channels);
-    volume.set(channels, vol_from_linear(vol));
-    ctx.introspect()
-        .set_sink_volume_by_name(sink, &volume, None);
+/// Parses the milliseconds of a volume command. [`None`] if `duration` is empty, for the
+/// default duration.
+fn parse_duration(duration: &str) -> Result<Option<f64>, String> {
+    if duration.is_empty() {
+        return Ok(None);
+    }
+    duration
+        .parse()
+        .ok()
+        .filter(|ms: &f64| ms.is_finite() && *ms >= 0.)
+        .map(Some)
+        .ok_or_else(|| format!("Invalid duration `{duration}`, expected milliseconds."))
 }
-fn vol_to_linear(volume: Volume) -> f64 {
-    (volume.0 as f64 / Volume::NORMAL.0 as f64 * 1e4).round() / 1e4
+/// Removes the whitespace before each `%`, so `50 %` reads as `50%`, and makes commas between
+/// digits decimal points if `decimal_comma`.
+fn normalize_volumes(command: &str, decimal_comma: bool) -> String {
+    let mut normalized = String::with_capacity(command.len());
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => {
+                let trimmed = normalized.trim_end().len();
+                normalized.truncate(trimmed);
+            }
+            ',' if decimal_comma
+                && normalized.ends_with(|c: char| c.is_ascii_digit())
+                && chars.peek().is_some_and(char::is_ascii_digit) =>
+            {
+                normalized.push('.');
+                continue;
+            }
+            _ => {}
+        }
+        normalized.push(c);
+    }
+    normalized
 }
-fn vol_from_linear(volume: f64) -> Volume {
-    Volume((volume * Volume::NORMAL.0 as f64) as u32)
+/// Parses a volume such as `0.4`, `+10%`, or `-3dB`.
+fn parse_volume(s: &str) -> Option<ChangeVolume> {
+    let relative = s.starts_with('+') || s.starts_with('-');
+    let num = if relative { &s[1..] } else { s };
+    let percent = num.ends_with('%');
+    let num = num.strip_suffix('%').unwrap_or(num);
+    let db = num.ends_with("dB") || num.ends_with("db");
+    let num = num
+        .strip_suffix("dB")
+        .or_else(|| num.strip_suffix("db"))
+        .unwrap_or(num);
+    let mut target: f64 = num.parse().ok()?;
+    if percent {
+        target /= 100.;
+    }
+
+    Some(if db {
+        if s.starts_with('-') {
+            target = -target;
+        }
+        if relative {
+            // adding decibels is multiplying the volume
+            ChangeVolume::Multiply(vol_from_db(target))
+        } else {
+            ChangeVolume::Absolute(vol_from_db(target))
+        }
+    } else if relative {
+        if s.starts_with('+') {
+            ChangeVolume::Increase(target)
+        } else {
+            ChangeVolume::Increase(-target)
+        }
+    } else {
+        ChangeVolume::Absolute(target)
+    })
 }
 
-fn socket_path() -> std::path::PathBuf {
-    let mut p = Path::new("/run").to_path_buf();
-    let user: u32 = unsafe { libc::getuid() };
-    if user != 0 {
-        p.push("user");
-        p.push(user.to_string());
-    }
-    p.push("pasvd");
-    p
+/// Binds the command socket with the permissions of `--socket-mode` and `--socket-group`.
+fn listen(path: &Path, mode: Option<u32>, group: Option<u32>) -> Result<UnixListener, String> {
+    let listener = bind(path)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .map_err(|err| format!("Failed to listen for commands from the user: {err}"))?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|err| format!("Failed to set the permissions of the socket: {err}"))?;
+    }
+    if let Some(group) = group {
+        std::os::unix::fs::chown(path, None, Some(group))
+            .map_err(|err| format!("Failed to set the group of the socket: {err}"))?;
+    }
+    Ok(listener)
+}
+/// Binds the socket anew after it failed, exiting if that keeps failing.
+fn rebind(path: &Path, mode: Option<u32>, group: Option<u32>) -> UnixListener {
+    let mut delay = REBIND_DELAY;
+    for _ in 0..REBIND_ATTEMPTS {
+        match listen(path, mode, group) {
+            Ok(listener) => {
+                eprintln!("Listening on {} again.", path.display());
+                return listener;
+            }
+            Err(err) => eprintln!("{err}"),
+        }
+        thread::sleep(delay);
+        delay *= 2;
+    }
+    eprintln!("Giving up on the socket after {REBIND_ATTEMPTS} attempts.");
+    process::exit(1);
+}
+/// Whether the socket file at `path` is gone. A file replaced by another daemon, such as one
+/// started with `--force`, is left to it. Abstract sockets have no file to remove.
+fn socket_removed(path: &Path) -> bool {
+    abstract_name(path).is_none()
+        && fs::symlink_metadata(path).is_err_and(|err| err.kind() == io::ErrorKind::NotFound)
+}
+/// Waits up to `timeout` for a connection to `listener`. Returns whether one came.
+fn wait_for_connection(listener: &UnixListener, timeout: Duration) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+    match unsafe { libc::poll(&mut fd, 1, timeout) } {
+        n if n > 0 => Ok(true),
+        0 => Ok(false),
+        _ => {
+            let err = io::Error::last_os_error();
+            // a signal, which isn't restarted for `poll`
+            if err.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+    }
+}
+/// Whether `accept` failed because of the connection or a lack of resources, which passes,
+/// instead of the socket itself.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::OutOfMemory
+    ) || matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EPROTO)
+    )
+}
+/// Listens on the socket at `path`, replacing any file there, or on the abstract address.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match abstract_name(path) {
+        Some(name) => UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?),
+        None => {
+            let _ = fs::remove_file(path);
+            UnixListener::bind(path)
+        }
+    }
+}
+/// Whether a daemon answers `ping` on the socket at `path`.
+fn is_running(path: &Path) -> bool {
+    let Ok(mut stream) = connect(path) else {
+        return false;
+    };
+    // a hung daemon doesn't keep this one from starting
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    let mut reply = String::new();
+    stream.write_all(b"ping").is_ok()
+        && stream.shutdown(std::net::Shutdown::Write).is_ok()
+        && stream.read_to_string(&mut reply).is_ok()
+        && reply.trim() == "pong"
+}
+/// Gets the id of a group by name or id.
+fn group_id(group: &str) -> Result<u32, String> {
+    if let Ok(id) = group.parse() {
+        return Ok(id);
+    }
+    let name = CString::new(group).map_err(|_| format!("Invalid group `{group}`."))?;
+    // only called before any other thread is started, so the static entry isn't overwritten
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("The group `{group}` doesn't exist."));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_socket_is_bound_again() {
+        let path = env::temp_dir().join(format!("pasvd-test-{}", process::id()));
+        let listener = listen(&path, None, None).unwrap();
+        assert!(!socket_removed(&path));
+        assert!(!wait_for_connection(&listener, Duration::ZERO).unwrap());
+
+        fs::remove_file(&path).unwrap();
+        // nothing fails, the file is just gone
+        assert!(!wait_for_connection(&listener, Duration::ZERO).unwrap());
+        assert!(socket_removed(&path));
+
+        let listener = rebind(&path, None, None);
+        assert!(!socket_removed(&path));
+        let _client = connect(&path).unwrap();
+        assert!(wait_for_connection(&listener, Duration::from_secs(1)).unwrap());
+        assert!(listener.accept().is_ok());
+
+        // replaced by another daemon, which keeps it
+        let _other = listen(&path, None, None).unwrap();
+        assert!(!socket_removed(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn transient_accept_errors() {
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(is_transient(&io::ErrorKind::ConnectionAborted.into()));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::EBADF)));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::EINVAL)));
+    }
+
+    #[test]
+    fn malformed_duration() {
+        assert_eq!(parse_duration(""), Ok(None));
+        assert_eq!(parse_duration("150"), Ok(Some(150.)));
+        for duration in ["abc", "15O", "-5", "inf", "NaN"] {
+            assert!(parse_duration(duration).is_err(), "{duration}");
+        }
+    }
+
+    #[test]
+    fn whitespace_before_percent() {
+        for command in ["50%", "50 %", "50  %", "50\t%"] {
+            assert_eq!(normalize_volumes(command, false), "50%");
+        }
+        assert_eq!(normalize_volumes(" +5 % 200 ", false), " +5% 200 ");
+        assert_eq!(
+            normalize_volumes("sink 50 % ease-in", false),
+            "sink 50% ease-in"
+        );
+        assert!(matches!(
+            parse_volume(normalize_volumes("-10 %", false).trim()),
+            Some(ChangeVolume::Increase(i)) if (i + 0.1).abs() < 1e-9
+        ));
+    }
+
+    #[test]
+    fn decimal_comma() {
+        assert_eq!(normalize_volumes("0,5", true), "0.5");
+        assert_eq!(normalize_volumes("+2,5 %", true), "+2.5%");
+        assert_eq!(normalize_volumes("0,5", false), "0,5");
+        // commas which aren't between digits are kept
+        assert_eq!(normalize_volumes("a,b 0,", true), "a,b 0,");
+    }
 }
@@ -0,0 +1,64 @@
+//! The log of `--record`, which appends each volume the transitions write to a file.
+//!
+//! Each line is the milliseconds since the start, the device, its name, and the volume, such as
+//! `1520.000 sink alsa_output.pci 0.5`.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use pa_smooth_volume::Watcher;
+
+/// How long written volumes can wait in the buffer before they're flushed to the file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Starts a thread which appends each volume sent to it to the file at `path`, timed from
+/// `start`. If the file can't be written, the error is printed and the thread stops, which
+/// drops the receiver, so the transitions go on without it.
+///
+/// The thread flushes and ends once the sender is dropped. Join it before the process exits.
+pub fn writer(path: PathBuf, start: Instant) -> (Watcher, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        let file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Failed to open {} to record to: {err}", path.display());
+                return;
+            }
+        };
+        let mut file = BufWriter::new(file);
+        let mut flushed = Instant::now();
+        loop {
+            let result = match rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok((device, name, volume)) => {
+                    let ms = start.elapsed().as_secs_f64() * 1e3;
+                    writeln!(file, "{ms:.3} {device} {name} {volume}").and_then(|()| {
+                        if flushed.elapsed() < FLUSH_INTERVAL {
+                            return Ok(());
+                        }
+                        flushed = Instant::now();
+                        file.flush()
+                    })
+                }
+                // idle, so everything recorded is in the file
+                Err(RecvTimeoutError::Timeout) => {
+                    flushed = Instant::now();
+                    file.flush()
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = file.flush();
+                    return;
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("Failed to record to {}, stopping: {err}", path.display());
+                return;
+            }
+        }
+    });
+    (tx, thread)
+}
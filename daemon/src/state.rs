@@ -0,0 +1,84 @@
+//! The volumes remembered by `--restore`, `$XDG_STATE_HOME/pasvd/last`.
+//!
+//! The file holds a line of the name and volume of each sink which was the default, such as
+//! `alsa_output.pci-0000_00_1f.3.analog-stereo=0.5`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::{env, fs, io, thread};
+
+/// `$XDG_STATE_HOME/pasvd/last`, falling back to `~/.local/state`.
+pub fn path() -> Option<PathBuf> {
+    let mut p = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    p.push("pasvd");
+    p.push("last");
+    Some(p)
+}
+/// Reads the remembered volume of `sink`. [`None`] if the file is missing or doesn't hold a
+/// volume of it.
+pub fn load(sink: &str) -> Option<f64> {
+    read(&path()?).remove(sink)
+}
+/// The remembered volumes by sink name, skipping corrupt lines.
+fn read(path: &Path) -> BTreeMap<String, f64> {
+    let Ok(file) = fs::read_to_string(path) else {
+        return BTreeMap::new();
+    };
+    file.lines()
+        .filter_map(|line| {
+            let (name, volume) = line.rsplit_once('=')?;
+            let volume: f64 = volume.trim().parse().ok()?;
+            // the change clamps it to the volume range like any other
+            (volume.is_finite() && volume >= 0.).then(|| (name.to_owned(), volume))
+        })
+        .collect()
+}
+/// Starts a thread which remembers each sink volume sent to it, so the main loop doesn't wait
+/// for the disk. The thread ends when the sender is dropped, after writing what was sent.
+pub fn writer(verbose: bool) -> (mpsc::Sender<(String, f64)>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        let Some(path) = path() else {
+            eprintln!("Neither XDG_STATE_HOME nor HOME is set, not remembering the volume.");
+            return;
+        };
+        let mut volumes = read(&path);
+        while let Ok((sink, volume)) = rx.recv() {
+            volumes.insert(sink, volume);
+            // only the last of a burst matters
+            while let Ok((sink, volume)) = rx.try_recv() {
+                volumes.insert(sink, volume);
+            }
+            match save(&path, &volumes) {
+                Ok(()) => {
+                    if verbose {
+                        eprintln!("Remembered the volumes {volumes:?}.");
+                    }
+                }
+                Err(err) => eprintln!("Failed to write {}: {err}", path.display()),
+            }
+        }
+    });
+    (tx, thread)
+}
+/// Writes `volumes` to a temporary file and renames it to `path`, so a crash never leaves a
+/// partial file.
+fn save(path: &Path, volumes: &BTreeMap<String, f64>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let lines: String = volumes
+        .iter()
+        .map(|(sink, volume)| format!("{sink}={volume}\n"))
+        .collect();
+    fs::write(&tmp, lines)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,50 @@
+//! The parts of the socket protocol shared by the daemon and `pasv`, without the daemon's
+//! dependency on libpulse.
+
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// The names of the easings, such as in volume changes and `--easing`.
+pub const EASINGS: [&str; 5] = ["linear", "ease-in", "ease-out", "ease-in-out", "ease-ends"];
+
+/// The code of the error of a missing default sink or source, as opposed to failing to query
+/// it. Errors are replied to as `ERR: <message>`, and the message of the ones with a code
+/// starts with `<CODE>: `.
+pub const NO_DEVICE: &str = "no-device";
+
+/// The default socket, `$XDG_RUNTIME_DIR/pasvd`, falling back to `/run/user/<UID>/pasvd`.
+/// Root's is `/run/pasvd`.
+pub fn socket_path() -> PathBuf {
+    let user: u32 = unsafe { libc::getuid() };
+    // root's socket is shared, whatever its session says
+    let mut p = match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) if user != 0 => PathBuf::from(dir),
+        _ => {
+            let mut p = Path::new("/run").to_path_buf();
+            if user != 0 {
+                p.push("user");
+                p.push(user.to_string());
+            }
+            p
+        }
+    };
+    p.push("pasvd");
+    p
+}
+/// The name of the abstract socket address, if `path` starts with `@`. Abstract sockets
+/// have no file, so there's nothing stale to remove.
+pub fn abstract_name(path: &Path) -> Option<&[u8]> {
+    path.as_os_str().as_bytes().strip_prefix(b"@")
+}
+/// Connects to the socket at `path`, which is an abstract socket address if it starts with
+/// `@`.
+pub fn connect(path: &Path) -> io::Result<UnixStream> {
+    match abstract_name(path) {
+        Some(name) => UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?),
+        None => UnixStream::connect(path),
+    }
+}